@@ -10,7 +10,7 @@ impl ConsoleCmd {
     pub async fn parse(input: &str) -> anyhow::Result<ConsoleCmd> {
         let (message_type, rem) = match input.find(" ") {
             Some(i) => (&input[..i], input[i+1..].trim()),
-            None => (&input[..], ""),
+            None => (input, ""),
         };
 
         match message_type {
@@ -3,13 +3,16 @@ use std::{fs, time::Duration};
 use byteorder::{LittleEndian, ReadBytesExt};
 use console_cmd::ConsoleCmd;
 use console_input::console_input_thread;
-use msgs::{client_server_msg::ClientServerMsg, dequeue::dequeue_msg, inter_client_msg::InterClientMsg, relay_server_connection_process::spawn_relay_server_connection_process};
+use logging::init_logging;
+use msgs::{auth::secret_from_env, client_server_msg::ClientServerMsg, dequeue::dequeue_msg, inter_client_msg::InterClientMsg, relay_server_connection_process::{spawn_relay_server_connection_process, RelayConnectionConfig}, tls::tls_pin_from_env};
+use tracing::error;
 
 mod console_cmd;
 mod console_input;
 
 #[tokio::main]
 async fn main() {
+    init_logging();
     let mut console_receiver = console_input_thread();
     loop {
         if let Some(console_str) = console_receiver.recv().await {
@@ -38,14 +41,14 @@ async fn main() {
 }
 
 fn display(log_bytes: &[u8]) {
-    let mut rdr = &log_bytes[..];
+    let mut rdr = log_bytes;
     let lines_to_print = 20;
     let mut line_nr = 0;
     let mut start_time = 0;
     let mut end_time = 0;
     let mut prev_timestamp = None;
     let mut time_stamp_buckets = Vec::new();
-    while rdr.len() > 0 {
+    while !rdr.is_empty() {
         let do_print = line_nr < lines_to_print;
         let timestamp = rdr.read_u32::<LittleEndian>().unwrap();
         if let Some(prev_timestamp) = prev_timestamp {
@@ -109,7 +112,7 @@ fn display(log_bytes: &[u8]) {
 }
 
 fn get_first_timestamp(log_bytes: &[u8]) -> u32 {
-    let mut rdr = &log_bytes[..];
+    let mut rdr = log_bytes;
     rdr.read_u32::<LittleEndian>().unwrap()
 }
 
@@ -124,11 +127,22 @@ async fn loop_play(log_bytes: Vec<u8>) {
 }
 
 async fn play_(log_bytes: &[u8]) {
+    let tls = match tls_pin_from_env() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{e:#}");
+            return;
+        }
+    };
     let (server_to_main, mut main_from_server) = tokio::sync::mpsc::channel(100);
-    let to_relay_server_process = spawn_relay_server_connection_process(server_to_main, false, 333);
-    let start_time = std::time::SystemTime::now().checked_sub(Duration::from_millis(get_first_timestamp(&log_bytes) as u64)).unwrap();
-    let mut rdr = &log_bytes[..];
-    while rdr.len() > 0 {
+    let to_relay_server_process = spawn_relay_server_connection_process(server_to_main, false, 333, RelayConnectionConfig {
+        secret: secret_from_env(),
+        tls,
+        ..Default::default()
+    });
+    let start_time = std::time::SystemTime::now().checked_sub(Duration::from_millis(get_first_timestamp(log_bytes) as u64)).unwrap();
+    let mut rdr = log_bytes;
+    while !rdr.is_empty() {
         let _recv_result = main_from_server.try_recv();
         let timestamp = rdr.read_u32::<LittleEndian>().unwrap() as i64;
         let since_start = start_time.elapsed().unwrap().as_millis() as i64;
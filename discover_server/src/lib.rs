@@ -1,3 +1,5 @@
+#![allow(clippy::not_unsafe_ptr_arg_deref, clippy::unnecessary_cast, clippy::needless_borrow, clippy::needless_return)]
+
 use std::{ffi::{c_char, CStr, CString}, ptr::null};
 use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent};
 
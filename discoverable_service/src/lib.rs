@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, time::Duration};
 
 use mdns_sd::{ServiceDaemon, ServiceInfo};
 
@@ -21,3 +21,11 @@ pub fn register_msdn(ip: IpAddr, port: u16, instance_name: &str) -> ServiceDaemo
     mdns.register(my_service).expect("Failed to register our service");
     mdns
 }
+
+pub fn unregister_msdn(mdns: ServiceDaemon, instance_name: &str) {
+    let fullname = format!("{instance_name}._{instance_name}._tcp.local.");
+    if let Ok(receiver) = mdns.unregister(&fullname) {
+        let _ = receiver.recv_timeout(Duration::from_secs(1));
+    }
+    let _ = mdns.shutdown();
+}
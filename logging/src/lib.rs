@@ -0,0 +1,48 @@
+use std::io::IsTerminal;
+
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+/// Environment variable with the initial filter, e.g. `info` or `server=debug,warp=warn`.
+pub const LOG_ENV_VAR: &str = "MUCO_LOG";
+/// Environment variable selecting the output format, `text` (default) or `json`.
+pub const LOG_FORMAT_ENV_VAR: &str = "MUCO_LOG_FORMAT";
+
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Changes the filter of the installed subscriber while the program runs.
+#[derive(Clone)]
+pub struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevelHandle {
+    pub fn current(&self) -> String {
+        self.handle.with_current(|filter| filter.to_string()).unwrap_or_default()
+    }
+
+    pub fn set(&self, directives: &str) -> anyhow::Result<()> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.handle.reload(filter)?;
+        Ok(())
+    }
+}
+
+/// Installs the global subscriber, falls back to `DEFAULT_LOG_FILTER` when `MUCO_LOG` is missing or invalid.
+pub fn init_logging() -> LogLevelHandle {
+    let filter = std::env::var(LOG_ENV_VAR).ok()
+        .and_then(|directives| EnvFilter::try_new(directives).ok())
+        .unwrap_or_else(|| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let json = std::env::var(LOG_FORMAT_ENV_VAR).is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    let text_layer = (!json).then(|| fmt::layer().with_ansi(std::io::stdout().is_terminal()));
+    let json_layer = json.then(|| fmt::layer().json().with_current_span(true).with_span_list(false));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_layer)
+        .with(json_layer)
+        .init();
+
+    LogLevelHandle { handle }
+}
@@ -1,32 +1,34 @@
 use std::io::stdin;
 use std::thread;
 
+use logging::LogLevelHandle;
+
 use crate::SAVE_DATA_PATH;
 use crate::context::MucoContextRef;
 use crate::status::Status;
 use crate::ws::{process_client_msg, ServerResponse};
 
-pub fn console_input_thread(context_ref: MucoContextRef) {
+pub fn console_input_thread(context_ref: MucoContextRef, log_level: LogLevelHandle) {
     thread::spawn(move || {
-        pollster::block_on(console_input_loop(context_ref))
+        pollster::block_on(console_input_loop(context_ref, log_level))
     });
 }
 
-pub async fn console_input_loop(context_ref: MucoContextRef) {
+pub async fn console_input_loop(context_ref: MucoContextRef, log_level: LogLevelHandle) {
     loop {
         let mut input = String::new();
         stdin().read_line(&mut input).unwrap();
-        match process_console_input(&input.trim(), &context_ref).await {
+        match process_console_input(input.trim(), &context_ref, &log_level).await {
             Ok(_) => {}
             Err(e) => println!("error: {e}")
         }
     }
 }
 
-pub async fn process_console_input(input: &str, context_ref: &MucoContextRef) -> anyhow::Result<()> {
+pub async fn process_console_input(input: &str, context_ref: &MucoContextRef, log_level: &LogLevelHandle) -> anyhow::Result<()> {
     let (message_type, rem) = match input.find(" ") {
         Some(i) => (&input[..i], input[i+1..].trim()),
-        None => (&input[..], ""),
+        None => (input, ""),
     };
 
     match message_type {
@@ -45,6 +47,12 @@ pub async fn process_console_input(input: &str, context_ref: &MucoContextRef) ->
             let json = serde_json::to_string_pretty(&status)?;
             println!("{json}");
         }
+        "log" => {
+            if !rem.is_empty() {
+                log_level.set(rem)?;
+            }
+            println!("log filter: {}", log_level.current());
+        }
         ">" => {
             let client_msg = serde_json::from_str(rem)?;
             let response = process_client_msg(client_msg, context_ref).await?;
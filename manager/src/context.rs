@@ -1,8 +1,9 @@
 use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Context;
-use msgs::{client_server_msg::{Address, ClientServerMsg}, inter_client_msg::InterClientMsg, player_data::{EnvData, EnvTrans, PlayerAttributeTag}, player_data_msg::PlayerDataMsg};
+use msgs::{client_server_msg::{Address, ClientServerMsg}, inter_client_msg::InterClientMsg, player_data::{EnvData, EnvTrans, PlayerAttribute}, player_data_msg::PlayerDataMsg};
 use tokio::sync::{RwLock, mpsc};
+use tracing::{info, warn};
 use warp::filters::ws::Message;
 
 use crate::{connection_status::ConnectionStatus, headset_data::{HeadsetData, DEFAULT_ENVIRONMENT_CODE}, status::{DeviceId, Status}};
@@ -13,7 +14,6 @@ pub struct MucoContext {
     pub connection_id_to_player: HashMap<u16, DeviceId>,
     pub status: Status,
     pub status_generation: usize,
-    pub unknown_connections: Vec<u16>,
 }
 
 pub type MucoContextRef = Arc<RwLock<MucoContext>>;
@@ -28,7 +28,7 @@ impl MucoContext {
         match self.status.environment_data.get(name) {
             Some(code) => code.to_owned(),
             None => {
-                println!("could not find environment code {name}, returning default");
+                warn!("could not find environment code {name}, returning default");
                 EnvData {
                     code: DEFAULT_ENVIRONMENT_CODE.into(),
                     transform: EnvTrans::default(),
@@ -45,11 +45,37 @@ impl MucoContext {
         }
     }
 
+    /// Marks the headset as connected through `connection_id` and sends it its settings.
+    pub async fn connect(&mut self, connection_id: u16, device_id: DeviceId) {
+        let headset = self.status.headsets.entry(device_id).or_insert_with(|| HeadsetData::new(device_id));
+        headset.temp.connection_status = ConnectionStatus::Connected (connection_id);
+        let color = headset.persistent.color;
+        let language = headset.persistent.language;
+        let environment_name = headset.persistent.environment_name.clone();
+        let environment_data = self.get_environment_data(&environment_name);
+        self.connection_id_to_player.insert(connection_id, device_id);
+        self.status_generation += 1;
+        self.send_msg_to_player(connection_id, InterClientMsg::PlayerData(PlayerDataMsg::Set(PlayerAttribute::Color (color)))).await;
+        self.send_msg_to_player(connection_id, InterClientMsg::PlayerData(PlayerDataMsg::Set(PlayerAttribute::Language (language)))).await;
+        self.send_msg_to_player(connection_id, InterClientMsg::PlayerData(PlayerDataMsg::Set(PlayerAttribute::EnvironmentData (environment_name, environment_data)))).await;
+    }
+
+    /// Forgets every connection, the session directory of a new hello replaces them.
+    pub fn disconnect_all(&mut self) {
+        for headset in self.status.headsets.values_mut() {
+            headset.temp.connection_status = ConnectionStatus::Disconnected;
+            headset.temp.latency = None;
+        }
+        self.connection_id_to_player.clear();
+        self.status_generation += 1;
+    }
+
     pub async fn disconnect(&mut self, connection_id: u16) {
         let Some(device_id) = self.connection_id_to_player.get(&connection_id) else { return };
         let Some(headset) = self.status.headsets.get_mut(device_id) else { return };
         headset.temp.connection_status = ConnectionStatus::Disconnected;
-        println!("client disconnected: {device_id}");
+        headset.temp.latency = None;
+        info!(session_id = connection_id, device_id = *device_id, "client disconnected");
         self.status_generation += 1;
     }
 
@@ -61,40 +87,8 @@ impl MucoContext {
         client_server_msg.pack(&mut client_server_msg_bytes);
         self.to_relay_server_process.send(client_server_msg_bytes).await.unwrap();
     }
-    
-    pub fn get_or_request_unique_device_id(&mut self, connection_id: u16) -> Option<u32> {
-        if let Some(unique_device_id) = self.connection_id_to_player.get(&connection_id) {
-            return Some(*unique_device_id)
-        }
-        if !self.unknown_connections.contains(&connection_id) {
-            self.unknown_connections.push(connection_id)
-        }
-        None
-    }
-
-    pub async fn request_unknown_device_ids(&mut self) {
-        while let Some(connection_id) = self.unknown_connections.pop() {
-            let msg = InterClientMsg::PlayerData(PlayerDataMsg::Request (PlayerAttributeTag::DeviceId));
-            self.send_msg_to_player(connection_id, msg).await;
-        }
-    }
 }
 
-pub async fn get_or_request_device_id(connection_id: u16, context_ref: &MucoContextRef) -> Option<u32> {
-    {
-        let read = context_ref.read().await;
-        if let Some(device_id) = read.connection_id_to_player.get(&connection_id) {
-            return Some(*device_id);
-        }
-        if read.unknown_connections.contains(&connection_id) {
-            return None;
-        }
-    }
-    {
-        let mut write = context_ref.write().await;
-        if !write.unknown_connections.contains(&connection_id) {
-            write.unknown_connections.push(connection_id)
-        }
-    }
-    None
+pub async fn device_id(connection_id: u16, context_ref: &MucoContextRef) -> Option<u32> {
+    context_ref.read().await.connection_id_to_player.get(&connection_id).copied()
 }
@@ -1,4 +1,4 @@
-use msgs::{color::Color, player_data::{BatteryStatus, DeviceStats, Language, TemperatureWarningLevel}};
+use msgs::{color::Color, latency::LatencyStats, player_data::{BatteryStatus, DeviceStats, Language, TemperatureWarningLevel}};
 
 use crate::{connection_status::ConnectionStatus, status::EnvCodeName, DEFAULT_SESSION_DURATION};
 
@@ -40,6 +40,8 @@ pub struct TempHeadsetData {
     pub in_dev_mode: bool,
     pub is_visible: bool,
     pub device_stats: DeviceStats,
+    /// Round trip times the relay server measured, `None` until it reported any.
+    pub latency: Option<LatencyStats>,
     pub data_buffer: Option<Vec<u8>>,
     pub level: f32,
     pub audio_volume: f32,
@@ -63,6 +65,7 @@ impl TempHeadsetData {
                 temperature_level: 0.0,
                 temperature_trend: 0.0,
             },
+            latency: None,
             level: 0.0,
             audio_volume:0.5,
         }
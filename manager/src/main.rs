@@ -2,10 +2,12 @@ use std::{collections::HashMap, convert::Infallible, net::{IpAddr, Ipv4Addr, Soc
 
 use console_input::console_input_thread;
 use context::{MucoContextRef, MucoContext};
-use msgs::{client_server_msg::ClientServerMsg, client_type::ClientType, relay_server_connection_process::spawn_relay_server_connection_process, server_client_msg::ServerClientMsg};
+use logging::init_logging;
+use msgs::{auth::secret_from_env, client_server_msg::ClientServerMsg, client_type::ClientType, relay_server_connection_process::{spawn_relay_server_connection_process, RelayConnectionConfig}, server_client_msg::ServerClientMsg, tls::tls_pin_from_env};
 use process_server_client_msg::process_server_client_msg;
 use status::Status;
 use tokio::sync::RwLock;
+use tracing::{error, info, warn};
 use warp::{reject::Rejection, Filter};
 
 mod connection_status;
@@ -24,16 +26,29 @@ const DEFAULT_SESSION_DURATION: i64 = 30 * 60;
 
 #[tokio::main]
 async fn main() {
+    let log_level = init_logging();
     let status = match Status::load(SAVE_DATA_PATH) {
         Ok(status) => status,
         Err(e) => {
-            println!("error while loading headset data at startup: {e}");
+            warn!("error while loading headset data at startup: {e}");
             Status::new()
         }
     };
 
+    let tls = match tls_pin_from_env() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{e:#}");
+            return;
+        }
+    };
+
     let (server_to_main, mut main_from_server) = tokio::sync::mpsc::channel(100);
-    let to_relay_server_process = spawn_relay_server_connection_process(server_to_main, true, 888);
+    let to_relay_server_process = spawn_relay_server_connection_process(server_to_main, true, 888, RelayConnectionConfig {
+        secret: secret_from_env(),
+        tls,
+        ..Default::default()
+    });
 
     {
         let msg = ClientServerMsg::SetClientType (ClientType::Manager);
@@ -48,12 +63,11 @@ async fn main() {
         to_frontend_senders: HashMap::new(),
         status,
         status_generation: 0,
-        unknown_connections: Vec::new(),
     };
 
     let context_ref = Arc::new(RwLock::new(context));
 
-    console_input_thread(context_ref.clone());
+    console_input_thread(context_ref.clone(), log_level);
 
     let health_route = warp::path!("health").and_then(handler::health_handler);
 
@@ -69,8 +83,7 @@ async fn main() {
     let port = 8080;
     let addr = SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), port);
 
-    // Print IP addresses and port
-    print_network_info(port).await;
+    log_network_info(port).await;
 
     tokio::spawn(async move {
         warp::serve(routes).run(addr).await;
@@ -84,7 +97,7 @@ async fn main() {
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
-                println!("error while decoding server client msg: {e}");
+                warn!("error while decoding server client msg: {e}");
                 continue;
             }
         };
@@ -105,11 +118,7 @@ async fn update_clients_periodically(context_ref: MucoContextRef) {
                     context.status.save(SAVE_DATA_PATH).unwrap();
                     frontend_status_generation = context.status_generation;
                 }
-                if context.unknown_connections.is_empty() {
-                    continue;
-                }
             }
-            context_ref.write().await.request_unknown_device_ids().await;
         }
     });
 }
@@ -118,31 +127,20 @@ fn with_context(context_ref: MucoContextRef) -> impl Filter<Extract = (MucoConte
     warp::any().map(move || context_ref.clone())
 }
 
-async fn print_network_info(port: u16) {
-    println!("=== Manager Server Starting ===");
+async fn log_network_info(port: u16) {
+    info!(port, "manager server starting");
 
     // Get local IP address
     match local_ip_address::local_ip() {
-        Ok(local_ip) => {
-            println!("Local IP:  {}:{}", local_ip, port);
-        }
-        Err(e) => {
-            println!("Could not determine local IP: {}", e);
-        }
+        Ok(local_ip) => info!("local ip: {local_ip}:{port}"),
+        Err(e) => warn!("could not determine local ip: {e}"),
     }
 
     // Get global/public IP address
     match get_public_ip().await {
-        Ok(public_ip) => {
-            println!("Global IP: {}:{}", public_ip, port);
-        }
-        Err(e) => {
-            println!("Could not determine global IP: {:?}", e);
-        }
+        Ok(public_ip) => info!("global ip: {public_ip}:{port}"),
+        Err(e) => warn!("could not determine global ip: {e:?}"),
     }
-
-    println!("Port:      {}", port);
-    println!("==============================");
 }
 
 async fn get_public_ip() -> std::result::Result<String, Box<dyn std::error::Error>> {
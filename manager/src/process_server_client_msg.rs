@@ -1,6 +1,7 @@
-use msgs::{inter_client_msg::InterClientMsg, player_data::{PlayerAttribute, PlayerAttributeTag}, player_data_msg::PlayerDataMsg, server_client_msg::ServerClientMsg};
+use msgs::{client_type::ClientType, inter_client_msg::InterClientMsg, player_data::{PlayerAttribute, PlayerAttributeTag}, player_data_msg::PlayerDataMsg, server_client_msg::ServerClientMsg};
+use tracing::{info, warn};
 
-use crate::{connection_status::ConnectionStatus, context::{get_or_request_device_id, MucoContextRef}, headset_data::HeadsetData};
+use crate::context::{device_id, MucoContextRef};
 
 pub async fn process_player_attribute(player_attribute: PlayerAttribute, sender: u16, context_ref: &MucoContextRef) {
     match player_attribute {
@@ -14,25 +15,10 @@ pub async fn process_player_attribute(player_attribute: PlayerAttribute, sender:
                 }
             }
 
-            let mut context = context_ref.write().await;
-            if !context.status.headsets.contains_key(&device_id) {
-                let new_player_data = HeadsetData::new(device_id);
-                context.status.headsets.insert(device_id, new_player_data);
-            }
-            let headset = context.status.headsets.get_mut(&device_id).unwrap();
-            headset.temp.connection_status = ConnectionStatus::Connected (sender);
-            let color = headset.persistent.color;
-            let language = headset.persistent.language;
-            let environment_name = headset.persistent.environment_name.clone();
-            let environment_data = context.get_environment_data(&environment_name);
-            context.connection_id_to_player.insert(sender, device_id);
-            context.status_generation += 1;
-            context.send_msg_to_player(sender, InterClientMsg::PlayerData(PlayerDataMsg::Set(PlayerAttribute::Color (color)))).await;
-            context.send_msg_to_player(sender, InterClientMsg::PlayerData(PlayerDataMsg::Set(PlayerAttribute::Language (language)))).await;
-            context.send_msg_to_player(sender, InterClientMsg::PlayerData(PlayerDataMsg::Set(PlayerAttribute::EnvironmentData (environment_name, environment_data)))).await;
+            context_ref.write().await.connect(sender, device_id).await;
         }
         _ => {
-            if let Some(device_id) = get_or_request_device_id(sender, context_ref).await {
+            if let Some(device_id) = device_id(sender, context_ref).await {
                 let update = {
                     let read = context_ref.read().await;
                     let headset = read.status.headsets.get(&device_id).unwrap();
@@ -63,11 +49,24 @@ pub async fn process_player_attribute(player_attribute: PlayerAttribute, sender:
 
 pub async fn process_server_client_msg(msg: ServerClientMsg<'_>, context_ref: &MucoContextRef) {
     match msg {
-        ServerClientMsg::Hello { session_id, model: _ } => {
-            println!("session id: {session_id}");
+        ServerClientMsg::Hello { session_id, sessions, .. } => {
+            info!(session_id, "connected to the relay server");
+            let mut context = context_ref.write().await;
+            context.disconnect_all();
+            for session in sessions {
+                if session.client_type == Some(ClientType::Player) {
+                    context.connect(session.session_id, session.device_id).await;
+                }
+            }
+        }
+        ServerClientMsg::ClientConnected { session_id, device_id, client_type } => {
+            info!(session_id, device_id, ?client_type, "client connected");
+            if client_type == ClientType::Player {
+                context_ref.write().await.connect(session_id, device_id).await;
+            }
         }
-        ServerClientMsg::ClientConnected(session_id) => {
-            println!("client connected: {session_id}");
+        ServerClientMsg::ClientResumed (session_id) => {
+            info!(session_id, "client resumed");
         }
         ServerClientMsg::ClientDisconnected(session_id) => {
             let mut context = context_ref.write().await;
@@ -78,7 +77,7 @@ pub async fn process_server_client_msg(msg: ServerClientMsg<'_>, context_ref: &M
             let inter_client_msg = match result {
                 Ok(msg) => msg,
                 Err(e) => {
-                    println!("error while decodeing msg: {e}");
+                    warn!(sender, "error while decoding msg: {e}");
                     return;
                 }
             };
@@ -89,7 +88,7 @@ pub async fn process_server_client_msg(msg: ServerClientMsg<'_>, context_ref: &M
                         PlayerDataMsg::Notify (player_data) => {
                             process_player_attribute(player_data, sender, context_ref).await;
                         }
-                        msg => println!("unhandeled player data msg: {msg:?}")
+                        msg => warn!(sender, "unhandled player data msg: {msg:?}")
                     }
                 }
                 InterClientMsg::_Ping => {}
@@ -97,23 +96,45 @@ pub async fn process_server_client_msg(msg: ServerClientMsg<'_>, context_ref: &M
                     process_data_buffer(data, sender, context_ref).await;
                 }
                 InterClientMsg::Diff (diff) => {
-                    let Some(devide_id) = get_or_request_device_id(sender, context_ref).await else { return };
+                    let Some(devide_id) = device_id(sender, context_ref).await else { return };
                     let data = {
                         let mut write = context_ref.write().await;
                         write.status.headsets.get_mut(&devide_id).unwrap().temp.data_buffer.take()
                     };
                     if let Some(mut data) = data {
-                        let mut rdr = &diff[..];
-                        apply_diff(&mut data, &mut rdr).unwrap();
+                        let rdr = &diff[..];
+                        apply_diff(&mut data, rdr).unwrap();
                         process_data_buffer(data, sender, context_ref).await;
                     }
                 }
             }
-
-            context_ref.write().await.get_or_request_unique_device_id(sender);
         }
         ServerClientMsg::DataNotify {..} => {}
         ServerClientMsg::DataOwner {..} => {},
+        ServerClientMsg::Ping => {}
+        ServerClientMsg::Pong => {}
+        ServerClientMsg::ServerShutdown => {
+            info!("relay server is shutting down");
+        }
+        ServerClientMsg::AuthChallenge (_) => {}
+        ServerClientMsg::HandshakeRejected { reason, supported_versions } => {
+            warn!("relay server rejected the connection: {reason}, it supports network versions {supported_versions}");
+        }
+        ServerClientMsg::Denied { msg_type, reason } => {
+            warn!(msg_type, %reason, "relay server denied message");
+        }
+        ServerClientMsg::UdpChannel { .. } => {}
+        ServerClientMsg::DataRemoved { .. } => {}
+        ServerClientMsg::SetDataResult { .. } => {}
+        ServerClientMsg::Ownership { .. } => {}
+        ServerClientMsg::LatencyProbe (_) => {}
+        ServerClientMsg::LatencyReport { session_id, stats } => {
+            let mut context = context_ref.write().await;
+            let Some(&device_id) = context.connection_id_to_player.get(&session_id) else { return };
+            let Some(headset) = context.status.headsets.get_mut(&device_id) else { return };
+            headset.temp.latency = Some(stats);
+            context.status_generation += 1;
+        }
     }
 }
 
@@ -126,10 +147,7 @@ pub async fn process_data_buffer(data: Vec<u8>, sender: u16, context_ref: &MucoC
             Ok(player_attribute) => {
                 process_player_attribute(player_attribute, sender, context_ref).await;
             }
-            Err(err) => {
-                dbg!(err);
-                // todo!()
-            }
+            Err(err) => warn!(sender, "error while decoding player attribute {tag:?}: {err:#}"),
         }
     }
     let mut write = context_ref.write().await;
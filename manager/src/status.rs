@@ -23,7 +23,7 @@ impl Status {
     pub fn new() -> Status {
         let mut environment_data = HashMap::new();
         let default_env_data = EnvData { code: DEFAULT_ENVIRONMENT_CODE.into(), transform: EnvTrans::default()};
-        environment_data.insert(DEFAULT_ENVIRONMENT_NAME.into(), default_env_data.into());
+        environment_data.insert(DEFAULT_ENVIRONMENT_NAME.into(), default_env_data);
         Status {
             headsets: HashMap::new(),
             environment_data,
@@ -31,7 +31,7 @@ impl Status {
     }
 
     pub fn save(&self, path: &str) -> anyhow::Result<()>{
-        let persistent_data = self.headsets.iter().map(|(_, headset_data)| headset_data.persistent.clone()).collect::<Vec<_>>();
+        let persistent_data = self.headsets.values().map(|headset_data| headset_data.persistent.clone()).collect::<Vec<_>>();
         let save_data = SaveData {
             headsets: persistent_data,
             environment_data: self.environment_data.clone(),
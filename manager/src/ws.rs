@@ -5,26 +5,31 @@ use anyhow::Context;
 use futures::{FutureExt, StreamExt};
 use msgs::{client_server_msg::ClientServerMsg, color::Color, inter_client_msg::InterClientMsg, player_data::{EnvData, Language, PlayerAttribute}, player_data_msg::PlayerDataMsg};
 use tokio::sync::mpsc;
+use tracing::{debug, info, info_span, warn, Instrument};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
 pub async fn frontend_connection_process(ws: WebSocket, context_ref: MucoContextRef) {
+    let id = Uuid::new_v4().as_simple().to_string();
+    let span = info_span!("frontend", frontend_id = %id);
+    frontend_connection(ws, id, context_ref).instrument(span).await
+}
+
+async fn frontend_connection(ws: WebSocket, id: String, context_ref: MucoContextRef) {
     let (frontend_ws_sender, mut frontend_ws_rcv) = ws.split();
     let (to_frontend_connection_process, front_end_connection_process_rcv) = mpsc::unbounded_channel();
 
     let front_end_connection_rcv_unbounded_receiver_stream = UnboundedReceiverStream::new(front_end_connection_process_rcv);
     tokio::task::spawn(front_end_connection_rcv_unbounded_receiver_stream.forward(frontend_ws_sender).map(|result| {
         if let Err(e) = result {
-            eprintln!("error sending websocket msg: {}", e);
+            warn!("error sending websocket msg: {e}");
         }
-    }));
-
-    let id = Uuid::new_v4().as_simple().to_string();
+    }).in_current_span());
 
     context_ref.write().await.to_frontend_senders.insert(id.clone(), to_frontend_connection_process);
 
-    println!("{} connected", id);
+    info!("connected");
 
     context_ref.write().await.status_generation += 1;
 
@@ -32,18 +37,18 @@ pub async fn frontend_connection_process(ws: WebSocket, context_ref: MucoContext
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
-                eprintln!("error receiving ws message for id: {}): {}", id.clone(), e);
+                warn!("error receiving ws message: {e}");
                 break;
             }
         };
         match client_msg(&id, msg, &context_ref).await {
             Ok(_) => {}
-            Err(e) => println!("error: {e}"),
+            Err(e) => warn!("error: {e}"),
         }
     }
 
     context_ref.write().await.to_frontend_senders.remove(&id);
-    println!("{} disconnected", id);
+    info!("disconnected");
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -211,7 +216,7 @@ pub async fn process_client_msg(client_msg: ClientMsg, context_ref: &MucoContext
             let mut headsets_to_update = Vec::new();
             for (headset_name, headset) in &context.status.headsets {
                 if headset.persistent.environment_name == env_name {
-                    headsets_to_update.push(headset_name.clone());
+                    headsets_to_update.push(*headset_name);
                 }
             }
             for headset_name in headsets_to_update {
@@ -235,7 +240,7 @@ pub async fn process_client_msg(client_msg: ClientMsg, context_ref: &MucoContext
             let code = environment_codes.get(&old_name).unwrap();
             environment_codes.insert(new_name.clone(), code.clone());
             environment_codes.remove(&old_name);
-            for (_, headset) in &mut context.status.headsets {
+            for headset in context.status.headsets.values_mut() {
                 if headset.persistent.environment_name == old_name {
                     headset.persistent.environment_name = new_name.clone();
                 }
@@ -266,7 +271,7 @@ pub async fn process_client_msg(client_msg: ClientMsg, context_ref: &MucoContext
 }
 
 async fn client_msg(id: &str, msg: Message, context_ref: &MucoContextRef) -> anyhow::Result<()> {
-    println!("received message from {}: {:?}", id, msg);
+    debug!("received message: {msg:?}");
     let message = msg.to_str().ok().context("could not get message")?.trim();
 
     let client_msg = serde_json::from_str::<ClientMsg>(message)?;
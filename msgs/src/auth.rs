@@ -0,0 +1,33 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const NONCE_LEN: usize = 32;
+pub const MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn keyed_mac(secret: &[u8], nonce: &[u8; NONCE_LEN], device_id: u32) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(nonce);
+    mac.update(&device_id.to_le_bytes());
+    mac
+}
+
+/// The answer to the server's challenge, binds the nonce to the device id announced in the handshake.
+pub fn auth_response(secret: &[u8], nonce: &[u8; NONCE_LEN], device_id: u32) -> [u8; MAC_LEN] {
+    keyed_mac(secret, nonce, device_id).finalize().into_bytes().into()
+}
+
+/// Compares in constant time.
+pub fn verify_auth_response(secret: &[u8], nonce: &[u8; NONCE_LEN], device_id: u32, response: &[u8; MAC_LEN]) -> bool {
+    keyed_mac(secret, nonce, device_id).verify_slice(response).is_ok()
+}
+
+/// Environment variable the relay server and its clients read the shared secret from.
+pub const SECRET_ENV_VAR: &str = "MUCO_SECRET";
+
+pub fn secret_from_env() -> Option<Vec<u8>> {
+    std::env::var(SECRET_ENV_VAR).ok()
+        .filter(|secret| !secret.is_empty())
+        .map(String::into_bytes)
+}
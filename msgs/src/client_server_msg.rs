@@ -1,23 +1,35 @@
-use std::io::{Cursor, Write};
+use std::{collections::HashSet, io::{Cursor, Write}};
 
 use anyhow::{bail, Context};
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 
-use crate::{client_type::ClientType, dequeue::dequeue_msg};
+use crate::{auth::MAC_LEN, client_type::ClientType, dequeue::dequeue_msg};
+
+/// Every session starts out as a member of this room.
+pub const DEFAULT_ROOM: u8 = 0;
+
+/// Bit in the flags of `SetData` that marks the fact as ephemeral.
+pub const EPHEMERAL_FLAG: u8 = 1;
+/// Bit in the flags of `SetData` that says an expected version follows the flags.
+pub const EXPECTED_VERSION_FLAG: u8 = 2;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Address {
     Client (u16),
     All,
     Other (u16),
+    Room (u8),
+    OtherInRoom (u8, u16),
 }
 
 impl Address {
-    pub fn includes(self, connection_id: u16) -> bool {
+    pub fn includes(self, connection_id: u16, rooms: &HashSet<u8>) -> bool {
         match self {
             Address::Client (addressed) => connection_id == addressed,
             Address::All => true,
             Address::Other (sender) => connection_id != sender,
+            Address::Room (room) => rooms.contains(&room),
+            Address::OtherInRoom (room, sender) => connection_id != sender && rooms.contains(&room),
         }
     }
 }
@@ -32,25 +44,89 @@ pub enum ClientServerMsg<'a> {
         room: u8,
         creator_id: u16,
         index: u16,
+        /// The server removes the fact when the writing session ends.
+        ephemeral: bool,
+        /// Only write if the fact still has this version, 0 for a fact that must not exist yet. The server
+        /// answers with `SetDataResult`.
+        expected_version: Option<u32>,
         data: &'a[u8]
     },
     ClaimData {
         room: u8,
         creator_id: u16,
         index: u16,
-    }
+    },
+    JoinRoom (u8),
+    LeaveRoom (u8),
+    ReleaseData {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+    },
+    Ping,
+    Pong,
+    AuthResponse ([u8; MAC_LEN]),
+    /// Relayed like `BinaryMessageTo`, but over the udp channel once there is one, so it may get lost or
+    /// arrive out of order. Meant for pose updates where only the latest one counts.
+    UnreliableMessageTo (Address, &'a [u8]),
+    /// Removes the fact along with its owner.
+    DeleteData {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+    },
+    /// Removes every fact in the room, refused if any of them is owned by another session.
+    ClearRoom (u8),
+    /// The owner's answer to a claim of `claimant` it was told about with `OwnershipEvent::Requested`.
+    AnswerClaim {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+        claimant: u16,
+        grant: bool,
+    },
+    /// Takes the fact over without asking the owner, only managers may do this.
+    ForceClaimData {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+    },
+    /// Sends the timestamp of a `LatencyProbe` straight back.
+    LatencyProbeEcho (u64),
 }
 
 impl<'a> ClientServerMsg<'a> {
-    pub fn dequeue_and_decode(input_buffer: &[u8], sender: u16) -> Option<(usize, anyhow::Result<ClientServerMsg>)> {
-        let Some((begin, end)) = dequeue_msg(input_buffer) else { return None };
+    /// Every name `variant_name` returns.
+    pub const VARIANT_NAMES: &'static [&'static str] = &[
+        "Disconnect",
+        "BinaryMessageTo",
+        "SetClientType",
+        "Kick",
+        "SetData",
+        "ClaimData",
+        "JoinRoom",
+        "LeaveRoom",
+        "ReleaseData",
+        "Ping",
+        "Pong",
+        "AuthResponse",
+        "UnreliableMessageTo",
+        "DeleteData",
+        "ClearRoom",
+        "AnswerClaim",
+        "ForceClaimData",
+        "LatencyProbeEcho",
+    ];
+
+    pub fn dequeue_and_decode(input_buffer: &[u8], sender: u16) -> Option<(usize, anyhow::Result<ClientServerMsg<'_>>)> {
+        let (begin, end) = dequeue_msg(input_buffer)?;
         let msg = Self::decode(&input_buffer[begin..end], sender);
         Some((end, msg))
     }
 
-    pub fn decode(input_buffer: &[u8], sender: u16) -> anyhow::Result<ClientServerMsg> {
+    pub fn decode(input_buffer: &[u8], sender: u16) -> anyhow::Result<ClientServerMsg<'_>> {
         let mut rdr = Cursor::new(&input_buffer);
-        let msg_type_index = rdr.read_u32::<LittleEndian>().unwrap();
+        let msg_type_index = rdr.read_u32::<LittleEndian>()?;
 
         let begin = 4;
 
@@ -67,41 +143,145 @@ impl<'a> ClientServerMsg<'a> {
                 ClientServerMsg::BinaryMessageTo (Address::Other (sender), bs)
             }
             3 => {
-                let session_id = rdr.read_u16::<LittleEndian>().unwrap();
+                let session_id = rdr.read_u16::<LittleEndian>()?;
                 let bs = &input_buffer[begin+2..];
                 ClientServerMsg::BinaryMessageTo (Address::Client(session_id), bs)
             }
             4 => {
-                let client_type_index = rdr.read_u32::<LittleEndian>().unwrap();
+                let client_type_index = rdr.read_u32::<LittleEndian>()?;
                 let client_type = ClientType::from_u32(client_type_index).context("unsupported client id")?;
                 ClientServerMsg::SetClientType (client_type)
             }
             5 => {
-                let session_id = rdr.read_u16::<LittleEndian>().unwrap();
+                let session_id = rdr.read_u16::<LittleEndian>()?;
                 ClientServerMsg::Kick (session_id)
             }
             6 => {
-                let room = rdr.read_u8().unwrap();
-                let creator_id = rdr.read_u16::<LittleEndian>().unwrap();
-                let index = rdr.read_u16::<LittleEndian>().unwrap();
-                let data = &input_buffer[begin+5..];
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                let flags = rdr.read_u8()?;
+                let expected_version = if flags & EXPECTED_VERSION_FLAG != 0 {
+                    Some(rdr.read_u32::<LittleEndian>()?)
+                }
+                else {
+                    None
+                };
+                let data = &input_buffer[rdr.position() as usize..];
                 ClientServerMsg::SetData {
                     room,
                     creator_id,
                     index,
+                    ephemeral: flags & EPHEMERAL_FLAG != 0,
+                    expected_version,
                     data,
                 }
             }
             7 => {
-                let room = rdr.read_u8().unwrap();
-                let creator_id = rdr.read_u16::<LittleEndian>().unwrap();
-                let index = rdr.read_u16::<LittleEndian>().unwrap();
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
                 ClientServerMsg::ClaimData {
                     room,
                     creator_id,
                     index,
                 }
             }
+            8 => {
+                let room = rdr.read_u8()?;
+                ClientServerMsg::JoinRoom (room)
+            }
+            9 => {
+                let room = rdr.read_u8()?;
+                ClientServerMsg::LeaveRoom (room)
+            }
+            10 => {
+                let room = rdr.read_u8()?;
+                let bs = &input_buffer[begin+1..];
+                ClientServerMsg::BinaryMessageTo (Address::Room (room), bs)
+            }
+            11 => {
+                let room = rdr.read_u8()?;
+                let bs = &input_buffer[begin+1..];
+                ClientServerMsg::BinaryMessageTo (Address::OtherInRoom (room, sender), bs)
+            }
+            12 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                ClientServerMsg::ReleaseData {
+                    room,
+                    creator_id,
+                    index,
+                }
+            }
+            13 => {
+                ClientServerMsg::Ping
+            }
+            14 => {
+                ClientServerMsg::Pong
+            }
+            15 => {
+                let Ok(response) = input_buffer[begin..].try_into() else {
+                    bail!("auth response has the wrong length");
+                };
+                ClientServerMsg::AuthResponse (response)
+            }
+            16 => {
+                let address_type_index = rdr.read_u8()?;
+                let address = match address_type_index {
+                    1 => Address::All,
+                    2 => Address::Other (sender),
+                    3 => Address::Client (rdr.read_u16::<LittleEndian>()?),
+                    10 => Address::Room (rdr.read_u8()?),
+                    11 => Address::OtherInRoom (rdr.read_u8()?, sender),
+                    address_type_index => bail!("unsupported address type: {address_type_index}"),
+                };
+                let bs = &input_buffer[rdr.position() as usize..];
+                ClientServerMsg::UnreliableMessageTo (address, bs)
+            }
+            17 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                ClientServerMsg::DeleteData {
+                    room,
+                    creator_id,
+                    index,
+                }
+            }
+            18 => {
+                let room = rdr.read_u8()?;
+                ClientServerMsg::ClearRoom (room)
+            }
+            19 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                let claimant = rdr.read_u16::<LittleEndian>()?;
+                let grant = rdr.read_u8()? != 0;
+                ClientServerMsg::AnswerClaim {
+                    room,
+                    creator_id,
+                    index,
+                    claimant,
+                    grant,
+                }
+            }
+            20 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                ClientServerMsg::ForceClaimData {
+                    room,
+                    creator_id,
+                    index,
+                }
+            }
+            21 => {
+                let timestamp = rdr.read_u64::<LittleEndian>()?;
+                ClientServerMsg::LatencyProbeEcho (timestamp)
+            }
             type_index => {
                 bail!("unsupported msg type: {type_index}");
             }
@@ -110,6 +290,59 @@ impl<'a> ClientServerMsg<'a> {
         Ok(msg)
     }
 
+    /// The type index `pack` writes for this message.
+    pub fn type_index(&self) -> u32 {
+        match self {
+            ClientServerMsg::Disconnect => 0,
+            ClientServerMsg::BinaryMessageTo (address, _) => match address {
+                Address::All => 1,
+                Address::Other (_) => 2,
+                Address::Client (_) => 3,
+                Address::Room (_) => 10,
+                Address::OtherInRoom (..) => 11,
+            },
+            ClientServerMsg::SetClientType (_) => 4,
+            ClientServerMsg::Kick (_) => 5,
+            ClientServerMsg::SetData { .. } => 6,
+            ClientServerMsg::ClaimData { .. } => 7,
+            ClientServerMsg::JoinRoom (_) => 8,
+            ClientServerMsg::LeaveRoom (_) => 9,
+            ClientServerMsg::ReleaseData { .. } => 12,
+            ClientServerMsg::Ping => 13,
+            ClientServerMsg::Pong => 14,
+            ClientServerMsg::AuthResponse (_) => 15,
+            ClientServerMsg::UnreliableMessageTo (..) => 16,
+            ClientServerMsg::DeleteData { .. } => 17,
+            ClientServerMsg::ClearRoom (_) => 18,
+            ClientServerMsg::AnswerClaim { .. } => 19,
+            ClientServerMsg::ForceClaimData { .. } => 20,
+            ClientServerMsg::LatencyProbeEcho (_) => 21,
+        }
+    }
+
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            ClientServerMsg::Disconnect => "Disconnect",
+            ClientServerMsg::BinaryMessageTo (..) => "BinaryMessageTo",
+            ClientServerMsg::SetClientType (_) => "SetClientType",
+            ClientServerMsg::Kick (_) => "Kick",
+            ClientServerMsg::SetData { .. } => "SetData",
+            ClientServerMsg::ClaimData { .. } => "ClaimData",
+            ClientServerMsg::JoinRoom (_) => "JoinRoom",
+            ClientServerMsg::LeaveRoom (_) => "LeaveRoom",
+            ClientServerMsg::ReleaseData { .. } => "ReleaseData",
+            ClientServerMsg::Ping => "Ping",
+            ClientServerMsg::Pong => "Pong",
+            ClientServerMsg::AuthResponse (_) => "AuthResponse",
+            ClientServerMsg::UnreliableMessageTo (..) => "UnreliableMessageTo",
+            ClientServerMsg::DeleteData { .. } => "DeleteData",
+            ClientServerMsg::ClearRoom (_) => "ClearRoom",
+            ClientServerMsg::AnswerClaim { .. } => "AnswerClaim",
+            ClientServerMsg::ForceClaimData { .. } => "ForceClaimData",
+            ClientServerMsg::LatencyProbeEcho (_) => "LatencyProbeEcho",
+        }
+    }
+
     pub fn pack(&self, wtr: &mut impl Write) {
         match self {
             ClientServerMsg::Disconnect => {
@@ -134,6 +367,18 @@ impl<'a> ClientServerMsg<'a> {
                         wtr.write_u32::<LittleEndian>(2).unwrap();
                         wtr.write_all(bytes).unwrap();
                     }
+                    Address::Room (room) => {
+                        wtr.write_u32::<LittleEndian>(5 + bytes.len() as u32).unwrap();
+                        wtr.write_u32::<LittleEndian>(10).unwrap();
+                        wtr.write_u8(*room).unwrap();
+                        wtr.write_all(bytes).unwrap();
+                    }
+                    Address::OtherInRoom (room, _) => {
+                        wtr.write_u32::<LittleEndian>(5 + bytes.len() as u32).unwrap();
+                        wtr.write_u32::<LittleEndian>(11).unwrap();
+                        wtr.write_u8(*room).unwrap();
+                        wtr.write_all(bytes).unwrap();
+                    }
                 }
             }
             ClientServerMsg::SetClientType (client_type) => {
@@ -146,12 +391,25 @@ impl<'a> ClientServerMsg<'a> {
                 wtr.write_u32::<LittleEndian>(5).unwrap();
                 wtr.write_u16::<LittleEndian>(*session_id).unwrap();
             }
-            ClientServerMsg::SetData { room, creator_id, index, data } => {
-                wtr.write_u32::<LittleEndian>(9 + data.len() as u32).unwrap();
+            ClientServerMsg::SetData { room, creator_id, index, ephemeral, expected_version, data } => {
+                let mut flags = 0;
+                if *ephemeral {
+                    flags |= EPHEMERAL_FLAG;
+                }
+                let mut expected_version_len = 0;
+                if expected_version.is_some() {
+                    flags |= EXPECTED_VERSION_FLAG;
+                    expected_version_len = 4;
+                }
+                wtr.write_u32::<LittleEndian>(10 + expected_version_len + data.len() as u32).unwrap();
                 wtr.write_u32::<LittleEndian>(6).unwrap();
                 wtr.write_u8(*room).unwrap();
                 wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
                 wtr.write_u16::<LittleEndian>(*index).unwrap();
+                wtr.write_u8(flags).unwrap();
+                if let Some(expected_version) = expected_version {
+                    wtr.write_u32::<LittleEndian>(*expected_version).unwrap();
+                }
                 wtr.write_all(data).unwrap();
             }
             ClientServerMsg::ClaimData { room, creator_id, index } => {
@@ -161,6 +419,86 @@ impl<'a> ClientServerMsg<'a> {
                 wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
                 wtr.write_u16::<LittleEndian>(*index).unwrap();
             }
+            ClientServerMsg::JoinRoom (room) => {
+                wtr.write_u32::<LittleEndian>(5).unwrap();
+                wtr.write_u32::<LittleEndian>(8).unwrap();
+                wtr.write_u8(*room).unwrap();
+            }
+            ClientServerMsg::LeaveRoom (room) => {
+                wtr.write_u32::<LittleEndian>(5).unwrap();
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_u8(*room).unwrap();
+            }
+            ClientServerMsg::ReleaseData { room, creator_id, index } => {
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_u32::<LittleEndian>(12).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+            }
+            ClientServerMsg::Ping => {
+                wtr.write_u32::<LittleEndian>(4).unwrap();
+                wtr.write_u32::<LittleEndian>(13).unwrap();
+            }
+            ClientServerMsg::Pong => {
+                wtr.write_u32::<LittleEndian>(4).unwrap();
+                wtr.write_u32::<LittleEndian>(14).unwrap();
+            }
+            ClientServerMsg::AuthResponse (response) => {
+                wtr.write_u32::<LittleEndian>(4 + MAC_LEN as u32).unwrap();
+                wtr.write_u32::<LittleEndian>(15).unwrap();
+                wtr.write_all(response).unwrap();
+            }
+            ClientServerMsg::UnreliableMessageTo (address, bytes) => {
+                // the address is written with the type index `BinaryMessageTo` uses for it
+                let address_len = match address {
+                    Address::All | Address::Other (_) => 0,
+                    Address::Client (_) => 2,
+                    Address::Room (_) | Address::OtherInRoom (..) => 1,
+                };
+                wtr.write_u32::<LittleEndian>(5 + address_len + bytes.len() as u32).unwrap();
+                wtr.write_u32::<LittleEndian>(16).unwrap();
+                wtr.write_u8(ClientServerMsg::BinaryMessageTo (*address, &[]).type_index() as u8).unwrap();
+                match address {
+                    Address::All | Address::Other (_) => {}
+                    Address::Client (session_id) => wtr.write_u16::<LittleEndian>(*session_id).unwrap(),
+                    Address::Room (room) | Address::OtherInRoom (room, _) => wtr.write_u8(*room).unwrap(),
+                }
+                wtr.write_all(bytes).unwrap();
+            }
+            ClientServerMsg::DeleteData { room, creator_id, index } => {
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_u32::<LittleEndian>(17).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+            }
+            ClientServerMsg::ClearRoom (room) => {
+                wtr.write_u32::<LittleEndian>(5).unwrap();
+                wtr.write_u32::<LittleEndian>(18).unwrap();
+                wtr.write_u8(*room).unwrap();
+            }
+            ClientServerMsg::AnswerClaim { room, creator_id, index, claimant, grant } => {
+                wtr.write_u32::<LittleEndian>(12).unwrap();
+                wtr.write_u32::<LittleEndian>(19).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+                wtr.write_u16::<LittleEndian>(*claimant).unwrap();
+                wtr.write_u8(*grant as u8).unwrap();
+            }
+            ClientServerMsg::ForceClaimData { room, creator_id, index } => {
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_u32::<LittleEndian>(20).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+            }
+            ClientServerMsg::LatencyProbeEcho (timestamp) => {
+                wtr.write_u32::<LittleEndian>(12).unwrap();
+                wtr.write_u32::<LittleEndian>(21).unwrap();
+                wtr.write_u64::<LittleEndian>(*timestamp).unwrap();
+            }
         }
     }
 }
@@ -1,7 +1,38 @@
-use std::io::Cursor;
+use std::{fmt, io::Cursor};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
+/// The length prefix counts the type index, so no frame can be shorter than this.
+pub const MIN_FRAME_LEN: usize = 4;
+
+/// Longest frame the server accepts from a client unless configured otherwise.
+pub const DEFAULT_MAX_CLIENT_FRAME_LEN: usize = 1024 * 1024;
+
+/// Longest frame a client accepts from the server unless configured otherwise, larger than the other way
+/// around because the hello carries the whole model.
+pub const DEFAULT_MAX_SERVER_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingError {
+    TooLong {
+        len: usize,
+        max_len: usize,
+    },
+    TooShort (usize),
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::TooLong { len, max_len } => write!(f, "frame of {len} bytes is longer than the maximum of {max_len} bytes"),
+            FramingError::TooShort (len) => write!(f, "frame of {len} bytes is too short to hold a message type"),
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+/// Returns where the first frame's message begins and ends, for buffers that only hold frames we packed ourselves.
 pub fn dequeue_msg(input_buffer: &[u8]) -> Option<(usize, usize)> {
     if input_buffer.len() < 4 {
         return None
@@ -11,10 +42,6 @@ pub fn dequeue_msg(input_buffer: &[u8]) -> Option<(usize, usize)> {
 
     let msg_ln = rdr.read_u32::<LittleEndian>().unwrap() as usize;
 
-    if msg_ln > 3000 {
-        println!("long message: {msg_ln}");
-    }
-
     let end = msg_ln + 4;
 
     if input_buffer.len() < end {
@@ -23,3 +50,69 @@ pub fn dequeue_msg(input_buffer: &[u8]) -> Option<(usize, usize)> {
 
     Some((4, end))
 }
+
+/// Like `dequeue_msg` but for bytes read from a peer, fails as soon as the length prefix is out of bounds
+/// instead of waiting for a frame that may never be complete.
+pub fn dequeue_frame(input_buffer: &[u8], max_frame_len: usize) -> Result<Option<(usize, usize)>, FramingError> {
+    if input_buffer.len() < 4 {
+        return Ok(None)
+    }
+
+    let mut rdr = Cursor::new(&input_buffer);
+
+    let msg_ln = rdr.read_u32::<LittleEndian>().unwrap() as usize;
+
+    if msg_ln > max_frame_len {
+        return Err(FramingError::TooLong { len: msg_ln, max_len: max_frame_len });
+    }
+    if msg_ln < MIN_FRAME_LEN {
+        return Err(FramingError::TooShort (msg_ln));
+    }
+
+    Ok(dequeue_msg(input_buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = len.to_le_bytes().to_vec();
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn waits_for_the_length_prefix_and_the_whole_frame() {
+        assert_eq!(dequeue_frame(&[8, 0, 0], 16), Ok(None));
+        assert_eq!(dequeue_frame(&frame(8, &[0; 7]), 16), Ok(None));
+        assert_eq!(dequeue_frame(&frame(8, &[0; 8]), 16), Ok(Some((4, 12))));
+    }
+
+    #[test]
+    fn only_returns_the_first_frame() {
+        let mut bytes = frame(4, &[1, 0, 0, 0]);
+        bytes.extend(frame(4, &[2, 0, 0, 0]));
+        assert_eq!(dequeue_frame(&bytes, 16), Ok(Some((4, 8))));
+    }
+
+    #[test]
+    fn accepts_frames_of_exactly_the_maximum() {
+        assert_eq!(dequeue_frame(&frame(16, &[0; 16]), 16), Ok(Some((4, 20))));
+        assert_eq!(dequeue_frame(&frame(16, &[]), 16), Ok(None));
+    }
+
+    #[test]
+    fn rejects_frames_longer_than_the_maximum_before_they_arrive() {
+        assert_eq!(dequeue_frame(&frame(17, &[]), 16), Err(FramingError::TooLong { len: 17, max_len: 16 }));
+        assert_eq!(dequeue_frame(&frame(u32::MAX, &[]), 16), Err(FramingError::TooLong { len: u32::MAX as usize, max_len: 16 }));
+    }
+
+    #[test]
+    fn rejects_frames_too_short_for_a_message_type() {
+        assert_eq!(dequeue_frame(&frame(MIN_FRAME_LEN as u32, &[0; 4]), 16), Ok(Some((4, 8))));
+        for len in 0..MIN_FRAME_LEN as u32 {
+            assert_eq!(dequeue_frame(&frame(len, &[0; 4]), 16), Err(FramingError::TooShort(len as usize)));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use tokio::time::{interval, Instant, Interval, MissedTickBehavior};
+
+pub enum HeartbeatAction {
+    Idle,
+    Ping,
+    TimedOut,
+}
+
+/// Tracks when the peer was last heard from. A ping is due once a third of the idle
+/// timeout has passed in silence, and the connection counts as dead after the full timeout.
+pub struct Heartbeat {
+    interval: Option<Interval>,
+    idle_timeout: Duration,
+    last_received: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(idle_timeout: Option<Duration>) -> Heartbeat {
+        let interval = idle_timeout.map(|idle_timeout| {
+            let mut interval = interval(idle_timeout / 3);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Heartbeat {
+            interval,
+            idle_timeout: idle_timeout.unwrap_or(Duration::MAX),
+            last_received: Instant::now(),
+        }
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    pub async fn tick(&mut self) -> HeartbeatAction {
+        let Some(interval) = &mut self.interval else {
+            return std::future::pending().await;
+        };
+        interval.tick().await;
+        let silence = self.last_received.elapsed();
+        if silence >= self.idle_timeout {
+            HeartbeatAction::TimedOut
+        }
+        else if silence >= interval.period() {
+            HeartbeatAction::Ping
+        }
+        else {
+            HeartbeatAction::Idle
+        }
+    }
+}
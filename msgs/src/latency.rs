@@ -0,0 +1,58 @@
+use std::io::{Cursor, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Wire size of `LatencyStats`.
+pub const LATENCY_STATS_LEN: usize = 20;
+
+/// Round trip times the server measured for one session, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LatencyStats {
+    pub rtt_ms: f32,
+    /// Moving average like tcp's smoothed rtt.
+    pub smoothed_rtt_ms: f32,
+    /// Mean deviation between consecutive round trips like rtp's interarrival jitter.
+    pub jitter_ms: f32,
+    pub min_rtt_ms: f32,
+    pub max_rtt_ms: f32,
+}
+
+impl LatencyStats {
+    /// Starts the statistics with the first measured round trip.
+    pub fn new(rtt_ms: f32) -> LatencyStats {
+        LatencyStats {
+            rtt_ms,
+            smoothed_rtt_ms: rtt_ms,
+            jitter_ms: 0.0,
+            min_rtt_ms: rtt_ms,
+            max_rtt_ms: rtt_ms,
+        }
+    }
+
+    pub fn record(&mut self, rtt_ms: f32) {
+        let delta = (rtt_ms - self.rtt_ms).abs();
+        self.jitter_ms += (delta - self.jitter_ms) / 16.0;
+        self.smoothed_rtt_ms += (rtt_ms - self.smoothed_rtt_ms) / 8.0;
+        self.min_rtt_ms = self.min_rtt_ms.min(rtt_ms);
+        self.max_rtt_ms = self.max_rtt_ms.max(rtt_ms);
+        self.rtt_ms = rtt_ms;
+    }
+
+    pub fn pack(&self, wtr: &mut impl Write) {
+        wtr.write_f32::<LittleEndian>(self.rtt_ms).unwrap();
+        wtr.write_f32::<LittleEndian>(self.smoothed_rtt_ms).unwrap();
+        wtr.write_f32::<LittleEndian>(self.jitter_ms).unwrap();
+        wtr.write_f32::<LittleEndian>(self.min_rtt_ms).unwrap();
+        wtr.write_f32::<LittleEndian>(self.max_rtt_ms).unwrap();
+    }
+
+    pub fn read(rdr: &mut Cursor<&&[u8]>) -> std::io::Result<LatencyStats> {
+        Ok(LatencyStats {
+            rtt_ms: rdr.read_f32::<LittleEndian>()?,
+            smoothed_rtt_ms: rdr.read_f32::<LittleEndian>()?,
+            jitter_ms: rdr.read_f32::<LittleEndian>()?,
+            min_rtt_ms: rdr.read_f32::<LittleEndian>()?,
+            max_rtt_ms: rdr.read_f32::<LittleEndian>()?,
+        })
+    }
+}
@@ -1,12 +1,19 @@
+pub mod auth;
 pub mod client_server_msg;
 pub mod client_type;
 pub mod color;
 pub mod dequeue;
 pub mod discover_server;
+pub mod heartbeat;
 pub mod inter_client_msg;
+pub mod latency;
 pub mod model;
 pub mod network_version;
 pub mod player_data_msg;
 pub mod player_data;
 pub mod relay_server_connection_process;
+pub mod relay_stream;
+pub mod resume;
 pub mod server_client_msg;
+pub mod tls;
+pub mod udp_channel;
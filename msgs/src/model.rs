@@ -1,8 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+/// A claim waiting for the owner's answer.
+#[derive(Debug, Clone, Copy)]
+pub struct ClaimRequest {
+    /// Tells a timed out request from a newer one of the same claimant.
+    pub id: u64,
+    pub claimant: u16,
+}
+
+#[derive(Default)]
 pub struct SharedData {
     pub model: Model,
     pub data_owners: HashMap<(u8, u16, u16), u16>,
+    /// Facts that go away with the session that wrote them.
+    pub ephemeral_writers: HashMap<(u8, u16, u16), u16>,
+    /// At most one pending claim per fact.
+    pub claim_requests: HashMap<(u8, u16, u16), ClaimRequest>,
+    pub next_claim_request_id: u64,
 }
 
 impl SharedData {
@@ -10,19 +24,147 @@ impl SharedData {
         SharedData {
             model: Model::new(),
             data_owners: HashMap::new(),
+            ephemeral_writers: HashMap::new(),
+            claim_requests: HashMap::new(),
+            next_claim_request_id: 0,
         }
     }
+
+    /// Returns whether the fact existed. Its owner and pending claim stay, releasing them is up to the caller.
+    pub fn remove_fact(&mut self, key: (u8, u16, u16)) -> bool {
+        self.ephemeral_writers.remove(&key);
+        self.model.remove(key)
+    }
+
+    /// Removes every ephemeral fact written by `session_id` and returns the removed facts.
+    pub fn remove_ephemeral(&mut self, session_id: u16) -> Vec<(u8, u16, u16)> {
+        let removed = self.ephemeral_writers.iter()
+            .filter(|(_, writer)| **writer == session_id)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in &removed {
+            self.remove_fact(*key);
+        }
+        removed
+    }
 }
 
 #[derive(Debug, Clone)]
+pub struct Fact {
+    /// Starts at `FIRST_FACT_VERSION` and goes up by one with every write.
+    pub version: u32,
+    pub data: Box<[u8]>,
+}
+
+/// Version of a newly written fact, a fact that does not exist has version 0.
+pub const FIRST_FACT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default)]
 pub struct Model {
-    pub facts: HashMap<(u8, u16, u16), Box<[u8]>>,
+    pub facts: HashMap<(u8, u16, u16), Fact>,
+    /// Highest version a removed fact of the room had. A fact written anew starts above it so a stale
+    /// expected version can not match the new fact, one entry per room keeps this bounded.
+    pub removed_high_water: HashMap<u8, u32>,
 }
 
 impl Model {
     pub fn new() -> Model {
         Model {
             facts: HashMap::new(),
+            removed_high_water: HashMap::new(),
+        }
+    }
+
+    /// Just the facts of `rooms`.
+    pub fn in_rooms(&self, rooms: &HashSet<u8>) -> Model {
+        let facts = self.facts.iter()
+            .filter(|((room, _, _), _)| rooms.contains(room))
+            .map(|(key, fact)| (*key, fact.clone()))
+            .collect();
+        Model {
+            facts,
+            ..Model::new()
         }
     }
+
+    /// 0 when the fact does not exist.
+    pub fn version(&self, key: (u8, u16, u16)) -> u32 {
+        self.facts.get(&key).map_or(0, |fact| fact.version)
+    }
+
+    /// Writes the fact and returns its new version.
+    pub fn set(&mut self, key: (u8, u16, u16), data: Box<[u8]>) -> u32 {
+        let last_version = match self.facts.get(&key) {
+            Some(fact) => fact.version,
+            None => self.removed_high_water.get(&key.0).copied().unwrap_or(0),
+        };
+        let version = last_version + 1;
+        self.facts.insert(key, Fact { version, data });
+        version
+    }
+
+    /// Returns whether the fact existed.
+    pub fn remove(&mut self, key: (u8, u16, u16)) -> bool {
+        match self.facts.remove(&key) {
+            Some(fact) => {
+                self.raise_removed_high_water(key.0, fact.version);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn raise_removed_high_water(&mut self, room: u8, version: u32) {
+        let high_water = self.removed_high_water.entry(room).or_insert(0);
+        *high_water = (*high_water).max(version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{client_server_msg::DEFAULT_ROOM, network_version::VersionRange, resume::NO_RESUME_TOKEN, server_client_msg::ServerClientMsg};
+
+    use super::*;
+
+    #[test]
+    fn versions_keep_going_up_after_a_remove() {
+        let mut model = Model::new();
+        assert_eq!(model.set((0, 1, 2), Box::new([])), FIRST_FACT_VERSION);
+        assert_eq!(model.set((0, 1, 2), Box::new([])), FIRST_FACT_VERSION + 1);
+        assert!(model.remove((0, 1, 2)));
+        assert_eq!(model.version((0, 1, 2)), 0);
+        assert_eq!(model.set((0, 1, 2), Box::new([])), FIRST_FACT_VERSION + 2);
+    }
+
+    #[test]
+    fn removed_facts_leave_one_version_per_room() {
+        let mut model = Model::new();
+        for index in 0..100 {
+            model.set((0, 1, index), Box::new([]));
+            model.set((0, 1, index), Box::new([]));
+            model.remove((0, 1, index));
+        }
+        assert_eq!(model.removed_high_water.len(), 1);
+        let high_water = model.removed_high_water[&0];
+        assert_eq!(model.set((0, 1, 0), Box::new([])), high_water + 1);
+        assert_eq!(model.set((1, 1, 0), Box::new([])), FIRST_FACT_VERSION);
+    }
+
+    #[test]
+    fn facts_of_other_rooms_are_not_in_the_hello() {
+        let mut model = Model::new();
+        model.set((DEFAULT_ROOM, 1, 2), vec![1].into_boxed_slice());
+        model.set((3, 1, 2), vec![2].into_boxed_slice());
+        let mut output_buffer = Vec::new();
+        let msg = ServerClientMsg::Hello {
+            session_id: 0,
+            resume_token: NO_RESUME_TOKEN,
+            supported_versions: VersionRange::default(),
+            sessions: Vec::new(),
+            model: model.in_rooms(&HashSet::from([DEFAULT_ROOM])),
+        };
+        msg.pack(&mut output_buffer);
+        let Ok(ServerClientMsg::Hello { model, .. }) = ServerClientMsg::decode(&output_buffer[4..]) else { panic!("not a hello") };
+        assert_eq!(model.facts.keys().copied().collect::<Vec<_>>(), vec![(DEFAULT_ROOM, 1, 2)]);
+    }
 }
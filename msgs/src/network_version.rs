@@ -1 +1,50 @@
-pub const NETWORK_VERSION_NUMBER: &[u8] = &[0, 0, 6];
+use std::fmt;
+
+use anyhow::{bail, Context};
+
+pub type NetworkVersion = [u8; 3];
+
+pub const NETWORK_VERSION: NetworkVersion = [0, 0, 19];
+pub const NETWORK_VERSION_NUMBER: &[u8] = &NETWORK_VERSION;
+
+/// Inclusive range of client network versions a server accepts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VersionRange {
+    pub min: NetworkVersion,
+    pub max: NetworkVersion,
+}
+
+impl VersionRange {
+    pub fn contains(&self, version: &[u8]) -> bool {
+        self.min[..] <= *version && *version <= self.max[..]
+    }
+}
+
+impl Default for VersionRange {
+    fn default() -> Self {
+        VersionRange {
+            min: NETWORK_VERSION,
+            max: NETWORK_VERSION,
+        }
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} to {}", format_network_version(&self.min), format_network_version(&self.max))
+    }
+}
+
+pub fn format_network_version(version: &[u8]) -> String {
+    version.iter().map(|part| part.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// Parses the dotted form `format_network_version` writes.
+pub fn parse_network_version(text: &str) -> anyhow::Result<NetworkVersion> {
+    let parts = text.split('.').collect::<Vec<_>>();
+    let [major, minor, patch] = parts[..] else {
+        bail!("network version has to look like 0.0.11, got {text}");
+    };
+    let parse = |part: &str| part.parse::<u8>().with_context(|| format!("invalid network version {text}"));
+    Ok([parse(major)?, parse(minor)?, parse(patch)?])
+}
@@ -135,8 +135,8 @@ impl PlayerAttributeTag {
 fn read_boxed_str(rdr: &mut impl Read) -> Box<str> {
     let len = rdr.read_u32::<LittleEndian>().unwrap();
     let mut buf = vec![0u8; len as usize];
-    rdr.read(&mut buf).unwrap();
-    let s: String = String::from_utf8(buf).unwrap().into();
+    rdr.read_exact(&mut buf).unwrap();
+    let s: String = String::from_utf8(buf).unwrap();
     s.into_boxed_str()
 }
 
@@ -224,18 +224,12 @@ impl PlayerAttribute {
             }
             PlayerAttributeTag::DevMode => {
                 let x = rdr.read_u8().unwrap();
-                let in_dev_mode = match x {
-                    0 => false,
-                    _ => true,
-                };
+                let in_dev_mode = x != 0;
                 PlayerAttribute::DevMode(in_dev_mode)
             }
             PlayerAttributeTag::IsVisible => {
                 let x = rdr.read_u8().unwrap();
-                let is_visible = match x {
-                    0 => false,
-                    _ => true,
-                };
+                let is_visible = x != 0;
                 PlayerAttribute::IsVisible(is_visible)
             }
             PlayerAttributeTag::DeviceStats => {
@@ -1,35 +1,174 @@
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, time::Duration};
+
 use byteorder::{ByteOrder, LittleEndian};
-use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}};
+use tokio::{net::{TcpStream, UdpSocket}, io::{AsyncReadExt, AsyncWriteExt}};
+use tracing::{field, info, info_span, warn, Instrument, Span};
+
+use crate::{auth::auth_response, client_server_msg::{Address, ClientServerMsg}, dequeue::{dequeue_frame, dequeue_msg, DEFAULT_MAX_SERVER_FRAME_LEN}, discover_server::find_local_server_ip, heartbeat::{Heartbeat, HeartbeatAction}, network_version::NETWORK_VERSION_NUMBER, relay_stream::BoxedRelayStream, resume::{pack_resume_request, ResumeToken, NO_RESUME_TOKEN}, server_client_msg::ServerClientMsg, tls::{connect_tls, pinned_tls_connector, CertificateFingerprint}, udp_channel::{decode_server_datagram, is_newer, pack_client_datagram, UdpToken}};
+
+pub struct RelayConnectionConfig {
+    /// Reconnect when nothing was heard from the server for this long, `None` disables the heartbeat.
+    pub idle_timeout: Option<Duration>,
+    /// Answers the server's authentication challenge, has to match the secret the server was started with.
+    pub secret: Option<Vec<u8>>,
+    /// Connects over tls and only trusts the server certificate with this fingerprint, `None` connects over plain tcp.
+    pub tls: Option<CertificateFingerprint>,
+    /// Reconnects when the server sends a frame longer than this.
+    pub max_frame_len: usize,
+}
+
+impl Default for RelayConnectionConfig {
+    fn default() -> Self {
+        RelayConnectionConfig {
+            idle_timeout: Some(Duration::from_secs(15)),
+            secret: None,
+            tls: None,
+            max_frame_len: DEFAULT_MAX_SERVER_FRAME_LEN,
+        }
+    }
+}
+
+/// The udp side channel of the current connection.
+struct UdpLink {
+    socket: UdpSocket,
+    session_id: u16,
+    token: UdpToken,
+    /// Set once the server answered a ping over udp, until then unreliable messages keep going over tcp.
+    confirmed: bool,
+    next_sequence: u32,
+    latest_from: HashMap<u16, u32>,
+}
+
+impl UdpLink {
+    async fn open(server_addr: SocketAddr, session_id: u16, token: UdpToken) -> std::io::Result<UdpLink> {
+        let unspecified = match server_addr.ip() {
+            IpAddr::V4 (_) => IpAddr::V4 (Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6 (_) => IpAddr::V6 (Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind((unspecified, 0)).await?;
+        socket.connect(server_addr).await?;
+        let mut link = UdpLink {
+            socket,
+            session_id,
+            token,
+            confirmed: false,
+            next_sequence: 0,
+            latest_from: HashMap::new(),
+        };
+        link.send_ping().await;
+        Ok(link)
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        let mut datagram = Vec::new();
+        pack_client_datagram(&mut datagram, self.session_id, &self.token, self.next_sequence, frame);
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    /// Registers our address with the server and keeps it fresh, lost pings are simply sent again on the next heartbeat.
+    async fn send_ping(&mut self) {
+        let mut output_buffer = Vec::new();
+        ClientServerMsg::Ping.pack(&mut output_buffer);
+        let _ = self.send(&output_buffer).await;
+    }
+
+    /// Returns the frame to hand to main, `None` for pongs and for stale or malformed datagrams.
+    fn receive<'a>(&mut self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let (sequence, frame) = decode_server_datagram(datagram)?;
+        match ServerClientMsg::decode(&frame[4..]) {
+            Ok(ServerClientMsg::Pong) => {
+                if !self.confirmed {
+                    info!("udp channel is up");
+                    self.confirmed = true;
+                }
+                None
+            }
+            Ok(ServerClientMsg::InterClient (sender, _)) => {
+                if self.latest_from.get(&sender).is_some_and(|latest| !is_newer(sequence, *latest)) {
+                    return None;
+                }
+                self.latest_from.insert(sender, sequence);
+                Some(frame)
+            }
+            _ => None,
+        }
+    }
+}
 
-use crate::{dequeue::dequeue_msg, discover_server::find_local_server_ip, network_version::NETWORK_VERSION_NUMBER};
+/// Network version, device id and resume request, flushed so the server sees them before we wait for its answer.
+async fn send_handshake(stream: &mut BoxedRelayStream, device_id: u32, resume: Option<(u16, ResumeToken)>) -> std::io::Result<()> {
+    stream.write_all(NETWORK_VERSION_NUMBER).await?;
+    let mut my_device_id = [0, 0, 0, 0];
+    LittleEndian::write_u32(&mut my_device_id, device_id);
+    stream.write_all(&my_device_id).await?;
+    let mut resume_request = Vec::new();
+    pack_resume_request(&mut resume_request, resume);
+    stream.write_all(&resume_request).await?;
+    stream.flush().await
+}
 
-pub fn spawn_relay_server_connection_process(server_to_main: tokio::sync::mpsc::Sender<Vec<u8>>, reconnect: bool, device_id: u32) -> tokio::sync::mpsc::Sender<Vec<u8>> {
+/// Single `UnreliableMessageTo` frames from main go over udp once the channel is confirmed.
+fn is_unreliable_message(frame: &[u8]) -> bool {
+    let unreliable_type_index = ClientServerMsg::UnreliableMessageTo (Address::All, &[]).type_index();
+    dequeue_msg(frame) == Some((4, frame.len())) && LittleEndian::read_u32(&frame[4..8]) == unreliable_type_index
+}
+
+pub fn spawn_relay_server_connection_process(server_to_main: tokio::sync::mpsc::Sender<Vec<u8>>, reconnect: bool, device_id: u32, config: RelayConnectionConfig) -> tokio::sync::mpsc::Sender<Vec<u8>> {
     let (main_to_server, mut server_from_main) = tokio::sync::mpsc::channel::<Vec<u8>>(100);
+    let span = info_span!("relay_connection", device_id, session_id = field::Empty, peer_addr = field::Empty);
     tokio::spawn(async move {
+        let tls_connector = config.tls.map(pinned_tls_connector);
+        // the session to ask for on the next connect, so a reconnect keeps our session id and ownerships
+        let mut resume: Option<(u16, ResumeToken)> = None;
         loop {
             let addr = match find_local_server_ip() {
                 Some(addr) => addr,
                 None => {
-                    println!("failed to find server, retrying in 5 seconds...");
+                    warn!("failed to find server, retrying in 5 seconds...");
                     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                     continue;
                 }
             };
-            println!("found server at address: {addr}");
+            Span::current().record("peer_addr", field::display(&addr));
+            info!("found server");
 
             let mut static_buffer = [0; 1024];
             let mut input_buffer = Vec::new();
 
-            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let stream = match TcpStream::connect(addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("failed to connect to the server: {e}, retrying in 5 seconds...");
+                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            // the udp channel goes to the same host
+            let server_ip = stream.peer_addr().ok().map(|peer_addr| peer_addr.ip());
+            let mut stream: BoxedRelayStream = match &tls_connector {
+                Some(connector) => match connect_tls(connector, stream).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(e) => {
+                        warn!("tls handshake with the server failed: {e}, retrying in 5 seconds...");
+                        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                        continue;
+                    }
+                },
+                None => Box::new(stream),
+            };
 
-            // Send initial handshake data
-            stream.write(NETWORK_VERSION_NUMBER).await.unwrap();
-            let mut my_device_id = [0, 0, 0, 0];
-            LittleEndian::write_u32(&mut my_device_id, device_id);
-            stream.write(&my_device_id).await.unwrap();
+            if let Err(e) = send_handshake(&mut stream, device_id, resume).await {
+                warn!("failed to send the handshake: {e}, retrying in 5 seconds...");
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                continue;
+            }
 
-            // Ensure data is flushed to server before entering select loop
-            stream.flush().await.unwrap();
+            let mut heartbeat = Heartbeat::new(config.idle_timeout);
+            let mut session_id = None;
+            let mut udp: Option<UdpLink> = None;
+            let mut udp_buffer = [0; 2048];
 
             'connected: loop {
                 tokio::select! {
@@ -38,15 +177,22 @@ pub fn spawn_relay_server_connection_process(server_to_main: tokio::sync::mpsc::
                         let msg = match result {
                             Some(msg) => msg,
                             None => {
-                                println!("server disconnected");
+                                info!("server disconnected");
                             break;
                             }
                         };
 
+                        if let Some(udp) = udp.as_mut().filter(|udp| udp.confirmed && is_unreliable_message(&msg)) {
+                            if let Err(err) = udp.send(&msg).await {
+                                warn!("error while sending over udp: {err}");
+                            }
+                            continue;
+                        }
+
                         match stream.write_all(&msg).await {
                             Ok(_) => {},
                             Err(err) => {
-                                println!("error while writing to stream: {err}, restarting connection process");
+                                warn!("error while writing to stream: {err}, restarting connection process");
                                 tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
                                 break 'connected;
                             },
@@ -56,27 +202,129 @@ pub fn spawn_relay_server_connection_process(server_to_main: tokio::sync::mpsc::
                         let len = match result {
                             Ok(len) => len,
                             Err(e) => {
-                                println!("error while reading from socket: {e}, restarting connection");
+                                warn!("error while reading from socket: {e}, restarting connection");
                                 tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
                                 break 'connected;
                             }
                         };
                         if len == 0 {
-                            println!("server died");
+                            warn!("server died");
                             break;
                         }
+                        heartbeat.received();
                         input_buffer.extend(&static_buffer[..len]);
                         
-                        while let Some((begin, end)) = dequeue_msg(&mut input_buffer) {
+                        loop {
+                            let (begin, end) = match dequeue_frame(&input_buffer, config.max_frame_len) {
+                                Ok(Some(frame)) => frame,
+                                Ok(None) => break,
+                                Err(err) => {
+                                    warn!("framing error: {err}, restarting connection");
+                                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                                    break 'connected;
+                                }
+                            };
                             let bytes = input_buffer[begin..end].to_vec();
+                            input_buffer.drain(..end);
+                            match ServerClientMsg::decode(&bytes) {
+                                Ok(ServerClientMsg::Ping) => {
+                                    let mut output_buffer = Vec::new();
+                                    ClientServerMsg::Pong.pack(&mut output_buffer);
+                                    if let Err(err) = stream.write_all(&output_buffer).await {
+                                        warn!("error while writing to stream: {err}, restarting connection process");
+                                        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                                        break 'connected;
+                                    }
+                                    continue;
+                                }
+                                Ok(ServerClientMsg::Pong) => continue,
+                                Ok(ServerClientMsg::LatencyProbe (timestamp)) => {
+                                    let mut output_buffer = Vec::new();
+                                    ClientServerMsg::LatencyProbeEcho (timestamp).pack(&mut output_buffer);
+                                    if let Err(err) = stream.write_all(&output_buffer).await {
+                                        warn!("error while writing to stream: {err}, restarting connection process");
+                                        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                                        break 'connected;
+                                    }
+                                    continue;
+                                }
+                                Ok(ServerClientMsg::AuthChallenge (nonce)) => {
+                                    let Some(secret) = &config.secret else {
+                                        warn!("server requires a shared secret but none is configured, retrying in 5 seconds...");
+                                        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                                        break 'connected;
+                                    };
+                                    let mut output_buffer = Vec::new();
+                                    ClientServerMsg::AuthResponse (auth_response(secret, &nonce, device_id)).pack(&mut output_buffer);
+                                    if let Err(err) = stream.write_all(&output_buffer).await {
+                                        warn!("error while writing to stream: {err}, restarting connection process");
+                                        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                                        break 'connected;
+                                    }
+                                    continue;
+                                }
+                                Ok(ServerClientMsg::Hello { session_id: my_session_id, resume_token, .. }) => {
+                                    session_id = Some(my_session_id);
+                                    Span::current().record("session_id", my_session_id);
+                                    resume = (resume_token != NO_RESUME_TOKEN).then_some((my_session_id, resume_token));
+                                }
+                                Ok(ServerClientMsg::UdpChannel { port, token }) => {
+                                    let (Some(session_id), Some(server_ip)) = (session_id, server_ip) else { continue };
+                                    match UdpLink::open(SocketAddr::new(server_ip, port), session_id, token).await {
+                                        Ok(link) => udp = Some(link),
+                                        Err(err) => warn!("failed to open the udp channel: {err}, sending everything over tcp"),
+                                    }
+                                    continue;
+                                }
+                                Ok(ServerClientMsg::HandshakeRejected { reason, supported_versions }) => {
+                                    warn!("server rejected the connection: {reason}, it supports network versions {supported_versions}");
+                                    let _ = server_to_main.send(bytes).await;
+                                    if reason.is_permanent() {
+                                        // dropping our ends of the channels tells main we gave up
+                                        return;
+                                    }
+                                    warn!("retrying in 5 seconds...");
+                                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                                    break 'connected;
+                                }
+                                _ => {}
+                            }
                             match server_to_main.send(bytes).await {
                                 Ok(_) => {}
                                 Err(_) => {
-                                    println!("main has gone away, closing");
+                                    info!("main has gone away, closing");
                                     return;
                                 }
                             }
-                            input_buffer.drain(..end);
+                        }
+                    }
+                    result = async { udp.as_ref().unwrap().socket.recv(&mut udp_buffer).await }, if udp.is_some() => {
+                        let Ok(len) = result else { continue };
+                        let Some(frame) = udp.as_mut().unwrap().receive(&udp_buffer[..len]) else { continue };
+                        if server_to_main.send(frame.to_vec()).await.is_err() {
+                            info!("main has gone away, closing");
+                            return;
+                        }
+                    }
+                    action = heartbeat.tick() => {
+                        if let Some(udp) = &mut udp {
+                            udp.send_ping().await;
+                        }
+                        match action {
+                            HeartbeatAction::Idle => {}
+                            HeartbeatAction::Ping => {
+                                let mut output_buffer = Vec::new();
+                                ClientServerMsg::Ping.pack(&mut output_buffer);
+                                if let Err(err) = stream.write_all(&output_buffer).await {
+                                    warn!("error while writing to stream: {err}, restarting connection process");
+                                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                                    break 'connected;
+                                }
+                            }
+                            HeartbeatAction::TimedOut => {
+                                warn!("server stopped responding, restarting connection");
+                                break 'connected;
+                            }
                         }
                     }
                 }
@@ -85,6 +333,6 @@ pub fn spawn_relay_server_connection_process(server_to_main: tokio::sync::mpsc::
                 return
             }
         }
-    });
+    }.instrument(span));
     main_to_server
 }
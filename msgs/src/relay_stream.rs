@@ -0,0 +1,8 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Any byte stream the relay protocol can run over, plain tcp or tls.
+pub trait RelayStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RelayStream for T {}
+
+pub type BoxedRelayStream = Box<dyn RelayStream>;
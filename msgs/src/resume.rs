@@ -0,0 +1,27 @@
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+pub const RESUME_TOKEN_LEN: usize = 16;
+
+pub type ResumeToken = [u8; RESUME_TOKEN_LEN];
+
+/// Sent in `Hello` when the server does not keep ended sessions around for resumption.
+pub const NO_RESUME_TOKEN: ResumeToken = [0; RESUME_TOKEN_LEN];
+
+/// Length of the resume block at the end of the handshake when the client asks for a resumption.
+pub const RESUME_REQUEST_LEN: usize = 1 + 2 + RESUME_TOKEN_LEN;
+
+/// The end of the handshake: a flag followed by the session id and token of the session to resume, if any.
+pub fn pack_resume_request(wtr: &mut impl Write, resume: Option<(u16, ResumeToken)>) {
+    match resume {
+        Some((session_id, token)) => {
+            wtr.write_u8(1).unwrap();
+            wtr.write_u16::<LittleEndian>(session_id).unwrap();
+            wtr.write_all(&token).unwrap();
+        }
+        None => {
+            wtr.write_u8(0).unwrap();
+        }
+    }
+}
@@ -3,93 +3,325 @@ use std::io::{Cursor, Read, Write};
 use anyhow::bail;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::{dequeue::dequeue_msg, model::Model};
+use crate::{auth::NONCE_LEN, client_type::ClientType, dequeue::dequeue_msg, latency::{LatencyStats, LATENCY_STATS_LEN}, model::{Fact, Model}, network_version::VersionRange, resume::{ResumeToken, RESUME_TOKEN_LEN}, udp_channel::{UdpToken, UDP_TOKEN_LEN}};
+
+/// Wire value of `DataOwner::owner_id` when nobody owns the fact.
+pub const NO_OWNER: u16 = u16::MAX;
+
+/// Wire value of a client type the session did not declare yet.
+pub const NO_CLIENT_TYPE: u32 = u32::MAX;
+
+/// A live session as listed in the hello.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionEntry {
+    pub session_id: u16,
+    pub device_id: u32,
+    pub client_type: Option<ClientType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    AuthenticationFailed,
+    AuthenticationTimedOut,
+    ServerFull,
+    UnsupportedVersion,
+}
+
+impl RejectReason {
+    pub fn from_u8(code: u8) -> Option<RejectReason> {
+        match code {
+            0 => Some(RejectReason::AuthenticationFailed),
+            1 => Some(RejectReason::AuthenticationTimedOut),
+            2 => Some(RejectReason::ServerFull),
+            3 => Some(RejectReason::UnsupportedVersion),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            RejectReason::AuthenticationFailed => 0,
+            RejectReason::AuthenticationTimedOut => 1,
+            RejectReason::ServerFull => 2,
+            RejectReason::UnsupportedVersion => 3,
+        }
+    }
+
+    /// Trying again will not help.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            RejectReason::AuthenticationFailed | RejectReason::UnsupportedVersion => true,
+            RejectReason::AuthenticationTimedOut | RejectReason::ServerFull => false,
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::AuthenticationFailed => write!(f, "authentication failed"),
+            RejectReason::AuthenticationTimedOut => write!(f, "no answer to the authentication challenge"),
+            RejectReason::ServerFull => write!(f, "the server has no free session id"),
+            RejectReason::UnsupportedVersion => write!(f, "the server does not support this network version"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenyReason {
+    ManagerOnly,
+    ManagerCredentialRequired,
+    RoomRestricted,
+    NotOwner,
+}
+
+impl DenyReason {
+    pub fn from_u8(code: u8) -> Option<DenyReason> {
+        match code {
+            0 => Some(DenyReason::ManagerOnly),
+            1 => Some(DenyReason::ManagerCredentialRequired),
+            2 => Some(DenyReason::RoomRestricted),
+            3 => Some(DenyReason::NotOwner),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            DenyReason::ManagerOnly => 0,
+            DenyReason::ManagerCredentialRequired => 1,
+            DenyReason::RoomRestricted => 2,
+            DenyReason::NotOwner => 3,
+        }
+    }
+}
+
+impl std::fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DenyReason::ManagerOnly => write!(f, "only managers may do this"),
+            DenyReason::ManagerCredentialRequired => write!(f, "managers have to authenticate with the manager secret"),
+            DenyReason::RoomRestricted => write!(f, "only managers may modify this room"),
+            DenyReason::NotOwner => write!(f, "the fact is owned by another session"),
+        }
+    }
+}
+
+/// Steps of handing a fact from its owner to another session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OwnershipEvent {
+    /// A session wants a fact that is owned already, the owner answers with `AnswerClaim`.
+    Requested,
+    Granted,
+    /// The owner refused, did not answer in time, another claim was pending or a manager took over.
+    Denied,
+    Released,
+}
+
+impl OwnershipEvent {
+    pub fn from_u8(code: u8) -> Option<OwnershipEvent> {
+        match code {
+            0 => Some(OwnershipEvent::Requested),
+            1 => Some(OwnershipEvent::Granted),
+            2 => Some(OwnershipEvent::Denied),
+            3 => Some(OwnershipEvent::Released),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            OwnershipEvent::Requested => 0,
+            OwnershipEvent::Granted => 1,
+            OwnershipEvent::Denied => 2,
+            OwnershipEvent::Released => 3,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum ServerClientMsg<'a> {
     Hello {
         session_id: u16,
+        /// Presented in a later handshake to get this session back, `NO_RESUME_TOKEN` if the server does not allow that.
+        resume_token: ResumeToken,
+        supported_versions: VersionRange,
+        /// Every other live session.
+        sessions: Vec<SessionEntry>,
         model: Model,
     },
-    ClientConnected (u16),
+    /// A session declared its client type.
+    ClientConnected {
+        session_id: u16,
+        device_id: u32,
+        client_type: ClientType,
+    },
     ClientDisconnected (u16),
+    /// A session that lost its connection is back with the same session id, ownerships and rooms.
+    ClientResumed (u16),
     InterClient (u16, &'a[u8]),
     DataNotify {
         room: u8,
         creator_id: u16,
         index: u16,
+        version: u32,
         data: &'a[u8]
     },
     DataOwner {
         room: u8,
         creator_id: u16,
         index: u16,
-        owner_id: u16,
-    }
+        owner_id: Option<u16>,
+    },
+    Ping,
+    Pong,
+    ServerShutdown,
+    /// Sent right after the handshake when the server requires a shared secret.
+    AuthChallenge ([u8; NONCE_LEN]),
+    /// The server closes the connection right after sending this. The layout of this message never changes,
+    /// so clients of any network version can tell why they were turned away.
+    HandshakeRejected {
+        reason: RejectReason,
+        supported_versions: VersionRange,
+    },
+    /// The server refused the client message with type index `msg_type`.
+    Denied {
+        msg_type: u32,
+        reason: DenyReason,
+    },
+    /// Sent right after the hello when the server relays `UnreliableMessageTo` over udp, the client sends its
+    /// datagrams to `port` on the server's address.
+    UdpChannel {
+        port: u16,
+        token: UdpToken,
+    },
+    /// The fact was deleted, together with its owner.
+    DataRemoved {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+    },
+    /// Answers a `SetData` with an expected version. On a conflict `version` and `data` are what the fact
+    /// holds now, version 0 and no data if it does not exist.
+    SetDataResult {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+        accepted: bool,
+        version: u32,
+        data: &'a[u8],
+    },
+    /// Broadcast to the room of the fact, `session_id` is the claimant or, for `Released`, the former owner.
+    Ownership {
+        room: u8,
+        creator_id: u16,
+        index: u16,
+        event: OwnershipEvent,
+        session_id: u16,
+    },
+    /// Carries a timestamp only the server can interpret, answered with `LatencyProbeEcho`.
+    LatencyProbe (u64),
+    /// Sent to managers whenever the server measured a round trip to `session_id`.
+    LatencyReport {
+        session_id: u16,
+        stats: LatencyStats,
+    },
 }
 
 impl<'a> ServerClientMsg<'a> {
-    pub fn dequeue_and_decode_(input_buffer: &mut Vec<u8>) -> Option<(usize, anyhow::Result<ServerClientMsg>)> {
-        let Some((begin, end)) = dequeue_msg(input_buffer) else { return None };
+    pub fn dequeue_and_decode_(input_buffer: &[u8]) -> Option<(usize, anyhow::Result<ServerClientMsg<'_>>)> {
+        let (begin, end) = dequeue_msg(input_buffer)?;
         let msg = Self::decode(&input_buffer[begin..end]);
         Some((end, msg))
     }
 
-    pub fn decode(input_buffer: &[u8]) -> anyhow::Result<ServerClientMsg> {
+    pub fn decode(input_buffer: &[u8]) -> anyhow::Result<ServerClientMsg<'_>> {
         let mut rdr = Cursor::new(&input_buffer);
-        let msg_type_index = rdr.read_u32::<LittleEndian>().unwrap();
+        let msg_type_index = rdr.read_u32::<LittleEndian>()?;
 
         let begin = 4;
 
         let msg = match msg_type_index {
             0 => {
-                let session_id = rdr.read_u16::<LittleEndian>().unwrap();
+                let session_id = rdr.read_u16::<LittleEndian>()?;
+                let mut resume_token = [0; RESUME_TOKEN_LEN];
+                rdr.read_exact(&mut resume_token)?;
+                let supported_versions = read_version_range(&mut rdr)?;
+                let session_count = rdr.read_u32::<LittleEndian>()?;
+                let mut sessions = Vec::new();
+                for _ in 0..session_count {
+                    let session_id = rdr.read_u16::<LittleEndian>()?;
+                    let device_id = rdr.read_u32::<LittleEndian>()?;
+                    let client_type = ClientType::from_u32(rdr.read_u32::<LittleEndian>()?);
+                    sessions.push(SessionEntry { session_id, device_id, client_type });
+                }
                 let mut model = Model::new();
-                let fact_count = rdr.read_u32::<LittleEndian>().unwrap();
+                let fact_count = rdr.read_u32::<LittleEndian>()?;
                 for _ in 0..fact_count {
-                    let room = rdr.read_u8().unwrap();
-                    let creator_id = rdr.read_u16::<LittleEndian>().unwrap();
-                    let index = rdr.read_u16::<LittleEndian>().unwrap();
-                    let len = rdr.read_u32::<LittleEndian>().unwrap();
-                    let mut buffer = vec![0u8; len as usize].into_boxed_slice();
-                    rdr.read_exact(&mut buffer).unwrap();
-                    model.facts.insert((room, creator_id, index), buffer);
+                    let room = rdr.read_u8()?;
+                    let creator_id = rdr.read_u16::<LittleEndian>()?;
+                    let index = rdr.read_u16::<LittleEndian>()?;
+                    let version = rdr.read_u32::<LittleEndian>()?;
+                    let len = rdr.read_u32::<LittleEndian>()? as usize;
+                    if len > input_buffer.len() - rdr.position() as usize {
+                        bail!("fact data runs past the end of the hello");
+                    }
+                    let mut data = vec![0u8; len].into_boxed_slice();
+                    rdr.read_exact(&mut data)?;
+                    model.facts.insert((room, creator_id, index), Fact { version, data });
                 }
                 ServerClientMsg::Hello {
                     session_id,
+                    resume_token,
+                    supported_versions,
+                    sessions,
                     model,
                 }
             }
             1 => {
-                let session_id = rdr.read_u16::<LittleEndian>().unwrap();
-                ServerClientMsg::ClientConnected (session_id)
+                let session_id = rdr.read_u16::<LittleEndian>()?;
+                let device_id = rdr.read_u32::<LittleEndian>()?;
+                let client_type_index = rdr.read_u32::<LittleEndian>()?;
+                let Some(client_type) = ClientType::from_u32(client_type_index) else {
+                    bail!("unsupported client type: {client_type_index}");
+                };
+                ServerClientMsg::ClientConnected {
+                    session_id,
+                    device_id,
+                    client_type,
+                }
             }
             2 => {
-                let session_id = rdr.read_u16::<LittleEndian>().unwrap();
+                let session_id = rdr.read_u16::<LittleEndian>()?;
                 ServerClientMsg::ClientDisconnected (session_id)
             }
             3 => {
-                let sender = rdr.read_u16::<LittleEndian>().unwrap();
+                let sender = rdr.read_u16::<LittleEndian>()?;
                 let bs = &input_buffer[begin+2..];
                 ServerClientMsg::InterClient (sender, bs)
             }
             4 => {
-                let room = rdr.read_u8().unwrap();
-                let creator_id = rdr.read_u16::<LittleEndian>().unwrap();
-                let index = rdr.read_u16::<LittleEndian>().unwrap();
-                let data = &input_buffer[begin+6..];
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                let version = rdr.read_u32::<LittleEndian>()?;
+                let data = &input_buffer[begin+9..];
                 ServerClientMsg::DataNotify {
                     room,
                     creator_id,
                     index,
+                    version,
                     data,
                 }
             }
             5 => {
-                let room = rdr.read_u8().unwrap();
-                let creator_id = rdr.read_u16::<LittleEndian>().unwrap();
-                let index = rdr.read_u16::<LittleEndian>().unwrap();
-                let owner_id = rdr.read_u16::<LittleEndian>().unwrap();
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                let owner_id = match rdr.read_u16::<LittleEndian>()? {
+                    NO_OWNER => None,
+                    owner_id => Some(owner_id),
+                };
                 ServerClientMsg::DataOwner {
                     room,
                     creator_id,
@@ -97,6 +329,111 @@ impl<'a> ServerClientMsg<'a> {
                     owner_id,
                 }
             }
+            6 => {
+                ServerClientMsg::Ping
+            }
+            7 => {
+                ServerClientMsg::Pong
+            }
+            8 => {
+                ServerClientMsg::ServerShutdown
+            }
+            9 => {
+                let Ok(nonce) = input_buffer[begin..].try_into() else {
+                    bail!("auth challenge has the wrong length");
+                };
+                ServerClientMsg::AuthChallenge (nonce)
+            }
+            10 => {
+                let code = rdr.read_u8()?;
+                let Some(reason) = RejectReason::from_u8(code) else {
+                    bail!("unsupported reject reason: {code}");
+                };
+                let supported_versions = read_version_range(&mut rdr)?;
+                ServerClientMsg::HandshakeRejected {
+                    reason,
+                    supported_versions,
+                }
+            }
+            11 => {
+                let msg_type = rdr.read_u32::<LittleEndian>()?;
+                let code = rdr.read_u8()?;
+                let Some(reason) = DenyReason::from_u8(code) else {
+                    bail!("unsupported deny reason: {code}");
+                };
+                ServerClientMsg::Denied {
+                    msg_type,
+                    reason,
+                }
+            }
+            12 => {
+                let session_id = rdr.read_u16::<LittleEndian>()?;
+                ServerClientMsg::ClientResumed (session_id)
+            }
+            13 => {
+                let port = rdr.read_u16::<LittleEndian>()?;
+                let mut token = [0; UDP_TOKEN_LEN];
+                rdr.read_exact(&mut token)?;
+                ServerClientMsg::UdpChannel {
+                    port,
+                    token,
+                }
+            }
+            14 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                ServerClientMsg::DataRemoved {
+                    room,
+                    creator_id,
+                    index,
+                }
+            }
+            15 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                let accepted = rdr.read_u8()? != 0;
+                let version = rdr.read_u32::<LittleEndian>()?;
+                let data = &input_buffer[begin+10..];
+                ServerClientMsg::SetDataResult {
+                    room,
+                    creator_id,
+                    index,
+                    accepted,
+                    version,
+                    data,
+                }
+            }
+            16 => {
+                let room = rdr.read_u8()?;
+                let creator_id = rdr.read_u16::<LittleEndian>()?;
+                let index = rdr.read_u16::<LittleEndian>()?;
+                let code = rdr.read_u8()?;
+                let Some(event) = OwnershipEvent::from_u8(code) else {
+                    bail!("unsupported ownership event: {code}");
+                };
+                let session_id = rdr.read_u16::<LittleEndian>()?;
+                ServerClientMsg::Ownership {
+                    room,
+                    creator_id,
+                    index,
+                    event,
+                    session_id,
+                }
+            }
+            17 => {
+                let timestamp = rdr.read_u64::<LittleEndian>()?;
+                ServerClientMsg::LatencyProbe (timestamp)
+            }
+            18 => {
+                let session_id = rdr.read_u16::<LittleEndian>()?;
+                let stats = LatencyStats::read(&mut rdr)?;
+                ServerClientMsg::LatencyReport {
+                    session_id,
+                    stats,
+                }
+            }
             type_index => {
                 bail!("unsupported msg type: {type_index}");
             }
@@ -107,31 +444,44 @@ impl<'a> ServerClientMsg<'a> {
 
     pub fn pack(&self, wtr: &mut impl Write) {
         match self {
-            ServerClientMsg::Hello { session_id, model } => {
+            ServerClientMsg::Hello { session_id, resume_token, supported_versions, sessions, model } => {
                 let mut facts_len = 0;
-                for (_, fact) in &model.facts {
-                    facts_len += 9;
-                    facts_len += fact.len();
+                for fact in model.facts.values() {
+                    facts_len += 13;
+                    facts_len += fact.data.len();
                 }
                 let model_len = 4 + facts_len;
-                let len = 6 + model_len;
+                let sessions_len = 4 + sessions.len() * 10;
+                let len = 6 + RESUME_TOKEN_LEN + 6 + sessions_len + model_len;
                 wtr.write_u32::<LittleEndian>(len as u32).unwrap();
                 wtr.write_u32::<LittleEndian>(0).unwrap();
                 wtr.write_u16::<LittleEndian>(*session_id).unwrap();
+                wtr.write_all(resume_token).unwrap();
+                wtr.write_all(&supported_versions.min).unwrap();
+                wtr.write_all(&supported_versions.max).unwrap();
+                wtr.write_u32::<LittleEndian>(sessions.len() as u32).unwrap();
+                for session in sessions {
+                    wtr.write_u16::<LittleEndian>(session.session_id).unwrap();
+                    wtr.write_u32::<LittleEndian>(session.device_id).unwrap();
+                    wtr.write_u32::<LittleEndian>(session.client_type.map_or(NO_CLIENT_TYPE, |client_type| client_type.as_u32())).unwrap();
+                }
                 wtr.write_u32::<LittleEndian>(model.facts.len() as u32).unwrap();
                 for ((room, creator_id, index), fact) in &model.facts {
                     wtr.write_u8(*room).unwrap();
                     wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
                     wtr.write_u16::<LittleEndian>(*index).unwrap();
-                    let len = fact.len();
+                    wtr.write_u32::<LittleEndian>(fact.version).unwrap();
+                    let len = fact.data.len();
                     wtr.write_u32::<LittleEndian>(len as u32).unwrap();
-                    wtr.write_all(&fact).unwrap();
+                    wtr.write_all(&fact.data).unwrap();
                 }
             }
-            ServerClientMsg::ClientConnected (id) => {
-                wtr.write_u32::<LittleEndian>(6).unwrap();
+            ServerClientMsg::ClientConnected { session_id, device_id, client_type } => {
+                wtr.write_u32::<LittleEndian>(14).unwrap();
                 wtr.write_u32::<LittleEndian>(1).unwrap();
-                wtr.write_u16::<LittleEndian>(*id).unwrap();
+                wtr.write_u16::<LittleEndian>(*session_id).unwrap();
+                wtr.write_u32::<LittleEndian>(*device_id).unwrap();
+                wtr.write_u32::<LittleEndian>(client_type.as_u32()).unwrap();
             }
             ServerClientMsg::ClientDisconnected (id) => {
                 wtr.write_u32::<LittleEndian>(6).unwrap();
@@ -144,12 +494,13 @@ impl<'a> ServerClientMsg<'a> {
                 wtr.write_u16::<LittleEndian>(*sender).unwrap();
                 wtr.write_all(bytes).unwrap();
             }
-            ServerClientMsg::DataNotify { room, creator_id, index, data } => {
-                wtr.write_u32::<LittleEndian>(9 + data.len() as u32).unwrap();
+            ServerClientMsg::DataNotify { room, creator_id, index, version, data } => {
+                wtr.write_u32::<LittleEndian>(13 + data.len() as u32).unwrap();
                 wtr.write_u32::<LittleEndian>(4).unwrap();
                 wtr.write_u8(*room).unwrap();
                 wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
                 wtr.write_u16::<LittleEndian>(*index).unwrap();
+                wtr.write_u32::<LittleEndian>(*version).unwrap();
                 wtr.write_all(data).unwrap();
             }
             ServerClientMsg::DataOwner { room, creator_id, index, owner_id } => {
@@ -158,7 +509,126 @@ impl<'a> ServerClientMsg<'a> {
                 wtr.write_u8(*room).unwrap();
                 wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
                 wtr.write_u16::<LittleEndian>(*index).unwrap();
-                wtr.write_u16::<LittleEndian>(*owner_id).unwrap();
+                wtr.write_u16::<LittleEndian>(owner_id.unwrap_or(NO_OWNER)).unwrap();
+            }
+            ServerClientMsg::Ping => {
+                wtr.write_u32::<LittleEndian>(4).unwrap();
+                wtr.write_u32::<LittleEndian>(6).unwrap();
+            }
+            ServerClientMsg::Pong => {
+                wtr.write_u32::<LittleEndian>(4).unwrap();
+                wtr.write_u32::<LittleEndian>(7).unwrap();
+            }
+            ServerClientMsg::ServerShutdown => {
+                wtr.write_u32::<LittleEndian>(4).unwrap();
+                wtr.write_u32::<LittleEndian>(8).unwrap();
+            }
+            ServerClientMsg::AuthChallenge (nonce) => {
+                wtr.write_u32::<LittleEndian>(4 + NONCE_LEN as u32).unwrap();
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_all(nonce).unwrap();
+            }
+            ServerClientMsg::HandshakeRejected { reason, supported_versions } => {
+                wtr.write_u32::<LittleEndian>(11).unwrap();
+                wtr.write_u32::<LittleEndian>(10).unwrap();
+                wtr.write_u8(reason.as_u8()).unwrap();
+                wtr.write_all(&supported_versions.min).unwrap();
+                wtr.write_all(&supported_versions.max).unwrap();
+            }
+            ServerClientMsg::Denied { msg_type, reason } => {
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_u32::<LittleEndian>(11).unwrap();
+                wtr.write_u32::<LittleEndian>(*msg_type).unwrap();
+                wtr.write_u8(reason.as_u8()).unwrap();
+            }
+            ServerClientMsg::ClientResumed (id) => {
+                wtr.write_u32::<LittleEndian>(6).unwrap();
+                wtr.write_u32::<LittleEndian>(12).unwrap();
+                wtr.write_u16::<LittleEndian>(*id).unwrap();
+            }
+            ServerClientMsg::UdpChannel { port, token } => {
+                wtr.write_u32::<LittleEndian>(6 + UDP_TOKEN_LEN as u32).unwrap();
+                wtr.write_u32::<LittleEndian>(13).unwrap();
+                wtr.write_u16::<LittleEndian>(*port).unwrap();
+                wtr.write_all(token).unwrap();
+            }
+            ServerClientMsg::DataRemoved { room, creator_id, index } => {
+                wtr.write_u32::<LittleEndian>(9).unwrap();
+                wtr.write_u32::<LittleEndian>(14).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+            }
+            ServerClientMsg::SetDataResult { room, creator_id, index, accepted, version, data } => {
+                wtr.write_u32::<LittleEndian>(14 + data.len() as u32).unwrap();
+                wtr.write_u32::<LittleEndian>(15).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+                wtr.write_u8(*accepted as u8).unwrap();
+                wtr.write_u32::<LittleEndian>(*version).unwrap();
+                wtr.write_all(data).unwrap();
+            }
+            ServerClientMsg::Ownership { room, creator_id, index, event, session_id } => {
+                wtr.write_u32::<LittleEndian>(12).unwrap();
+                wtr.write_u32::<LittleEndian>(16).unwrap();
+                wtr.write_u8(*room).unwrap();
+                wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+                wtr.write_u16::<LittleEndian>(*index).unwrap();
+                wtr.write_u8(event.as_u8()).unwrap();
+                wtr.write_u16::<LittleEndian>(*session_id).unwrap();
+            }
+            ServerClientMsg::LatencyProbe (timestamp) => {
+                wtr.write_u32::<LittleEndian>(12).unwrap();
+                wtr.write_u32::<LittleEndian>(17).unwrap();
+                wtr.write_u64::<LittleEndian>(*timestamp).unwrap();
+            }
+            ServerClientMsg::LatencyReport { session_id, stats } => {
+                wtr.write_u32::<LittleEndian>(6 + LATENCY_STATS_LEN as u32).unwrap();
+                wtr.write_u32::<LittleEndian>(18).unwrap();
+                wtr.write_u16::<LittleEndian>(*session_id).unwrap();
+                stats.pack(wtr);
+            }
+        }
+    }
+}
+
+fn read_version_range(rdr: &mut Cursor<&&[u8]>) -> anyhow::Result<VersionRange> {
+    let mut min = [0; 3];
+    rdr.read_exact(&mut min)?;
+    let mut max = [0; 3];
+    rdr.read_exact(&mut max)?;
+    Ok(VersionRange { min, max })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{model::Model, resume::NO_RESUME_TOKEN};
+
+    use super::*;
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut model = Model::new();
+        model.set((0, 1, 2), vec![1, 2, 3].into_boxed_slice());
+        let msgs = [
+            ServerClientMsg::Hello {
+                session_id: 1,
+                resume_token: NO_RESUME_TOKEN,
+                supported_versions: VersionRange::default(),
+                sessions: vec![SessionEntry { session_id: 1, device_id: 2, client_type: None }],
+                model,
+            },
+            ServerClientMsg::ClientResumed (1),
+            ServerClientMsg::DataOwner { room: 0, creator_id: 1, index: 2, owner_id: None },
+        ];
+        for msg in msgs {
+            let mut output_buffer = Vec::new();
+            msg.pack(&mut output_buffer);
+            let frame = &output_buffer[4..];
+            assert!(ServerClientMsg::decode(frame).is_ok());
+            for len in 0..frame.len() {
+                assert!(ServerClientMsg::decode(&frame[..len]).is_err(), "{msg:?} cut to {len} bytes");
             }
         }
     }
@@ -0,0 +1,115 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use anyhow::{bail, Context};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// Environment variable holding the hex encoded fingerprint clients pin the relay server's certificate to.
+pub const TLS_FINGERPRINT_ENV_VAR: &str = "MUCO_TLS_FINGERPRINT";
+/// Environment variable holding the path of a pem file with the certificate clients pin, used when no fingerprint is given.
+pub const TLS_CERT_ENV_VAR: &str = "MUCO_TLS_CERT";
+
+/// Sha256 over the der encoded certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertificateFingerprint(pub [u8; 32]);
+
+impl CertificateFingerprint {
+    pub fn of_certificate(certificate: &[u8]) -> CertificateFingerprint {
+        CertificateFingerprint(Sha256::digest(certificate).into())
+    }
+
+    /// Accepts the format `Display` writes as well as plain hex.
+    pub fn from_hex(text: &str) -> anyhow::Result<CertificateFingerprint> {
+        let digits = text.trim().chars().filter(|c| *c != ':').collect::<Vec<_>>();
+        if digits.len() != 64 {
+            bail!("a certificate fingerprint has 32 bytes, got {text}");
+        }
+        let mut fingerprint = [0; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            let pair = pair.iter().collect::<String>();
+            *byte = u8::from_str_radix(&pair, 16).with_context(|| format!("invalid certificate fingerprint {text}"))?;
+        }
+        Ok(CertificateFingerprint(fingerprint))
+    }
+
+    /// Fingerprint of the first certificate in a pem file.
+    pub fn of_pem_file(path: &Path) -> anyhow::Result<CertificateFingerprint> {
+        let certificate = CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("failed to read certificate {}", path.display()))?
+            .next()
+            .with_context(|| format!("no certificate in {}", path.display()))?
+            .with_context(|| format!("failed to parse certificate {}", path.display()))?;
+        Ok(CertificateFingerprint::of_certificate(&certificate))
+    }
+}
+
+impl fmt::Display for CertificateFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = self.0.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>();
+        write!(f, "{}", hex.join(":"))
+    }
+}
+
+/// `None` when neither variable is set, clients then connect without tls.
+pub fn tls_pin_from_env() -> anyhow::Result<Option<CertificateFingerprint>> {
+    if let Some(fingerprint) = std::env::var(TLS_FINGERPRINT_ENV_VAR).ok().filter(|fingerprint| !fingerprint.is_empty()) {
+        return CertificateFingerprint::from_hex(&fingerprint).map(Some);
+    }
+    if let Some(path) = std::env::var_os(TLS_CERT_ENV_VAR).filter(|path| !path.is_empty()) {
+        return CertificateFingerprint::of_pem_file(Path::new(&path)).map(Some);
+    }
+    Ok(None)
+}
+
+/// Trusts exactly the pinned certificate, relay servers run on venue networks without a public name or ca.
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    pin: CertificateFingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        if CertificateFingerprint::of_certificate(end_entity) != self.pin {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub fn pinned_tls_connector(pin: CertificateFingerprint) -> TlsConnector {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PinnedCertificateVerifier { pin, provider: provider.clone() };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+pub async fn connect_tls(connector: &TlsConnector, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+    // the name is not checked against the pinned certificate, it only ends up in the sni extension
+    let server_name = ServerName::IpAddress(stream.peer_addr()?.ip().into());
+    connector.connect(server_name, stream).await
+}
@@ -0,0 +1,64 @@
+use std::io::Write;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::dequeue::dequeue_msg;
+
+pub const UDP_TOKEN_LEN: usize = 16;
+
+/// Handed out with `ServerClientMsg::UdpChannel`, ties the datagrams of a client to its session.
+pub type UdpToken = [u8; UDP_TOKEN_LEN];
+
+const CLIENT_DATAGRAM_HEADER_LEN: usize = 2 + UDP_TOKEN_LEN + 4;
+const SERVER_DATAGRAM_HEADER_LEN: usize = 4;
+
+/// Layout: session id, token, sequence number, then one framed `ClientServerMsg`, a `Ping` registers the
+/// address the datagram came from and gets a `Pong` datagram back.
+pub fn pack_client_datagram(wtr: &mut impl Write, session_id: u16, token: &UdpToken, sequence: u32, frame: &[u8]) {
+    wtr.write_u16::<LittleEndian>(session_id).unwrap();
+    wtr.write_all(token).unwrap();
+    wtr.write_u32::<LittleEndian>(sequence).unwrap();
+    wtr.write_all(frame).unwrap();
+}
+
+/// Returns session id, token, sequence number and the body of the framed message, `None` for malformed datagrams.
+pub fn decode_client_datagram(datagram: &[u8]) -> Option<(u16, UdpToken, u32, &[u8])> {
+    if datagram.len() < CLIENT_DATAGRAM_HEADER_LEN {
+        return None;
+    }
+    let session_id = LittleEndian::read_u16(datagram);
+    let token = datagram[2..2 + UDP_TOKEN_LEN].try_into().unwrap();
+    let sequence = LittleEndian::read_u32(&datagram[2 + UDP_TOKEN_LEN..]);
+    let frame = &datagram[CLIENT_DATAGRAM_HEADER_LEN..];
+    let (begin, end) = dequeue_msg(frame)?;
+    if end != frame.len() {
+        return None;
+    }
+    Some((session_id, token, sequence, &frame[begin..end]))
+}
+
+/// Layout: sequence number, then one framed `ServerClientMsg`. The server numbers the messages of every
+/// sender on its own, so receivers can drop what is older than the latest they saw from that sender.
+pub fn pack_server_datagram(wtr: &mut impl Write, sequence: u32, frame: &[u8]) {
+    wtr.write_u32::<LittleEndian>(sequence).unwrap();
+    wtr.write_all(frame).unwrap();
+}
+
+/// Returns the sequence number and the framed message, ready to be decoded like one read from the tcp stream.
+pub fn decode_server_datagram(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    if datagram.len() < SERVER_DATAGRAM_HEADER_LEN {
+        return None;
+    }
+    let sequence = LittleEndian::read_u32(datagram);
+    let frame = &datagram[SERVER_DATAGRAM_HEADER_LEN..];
+    let (_, end) = dequeue_msg(frame)?;
+    if end != frame.len() {
+        return None;
+    }
+    Some((sequence, frame))
+}
+
+/// Sequence numbers wrap around, anything up to half the number space ahead counts as newer.
+pub fn is_newer(sequence: u32, latest: u32) -> bool {
+    (sequence.wrapping_sub(latest) as i32) > 0
+}
@@ -4,6 +4,8 @@ use std::{fs, net::{IpAddr, Ipv4Addr, SocketAddr}};
 use bytes::Bytes;
 use discoverable_service::register_msdn;
 use local_ip_address::local_ip;
+use logging::init_logging;
+use tracing::info;
 use warp::Filter;
 
 const FOLDER_NAME: &str = "photos";
@@ -32,12 +34,14 @@ async fn handle_upload(bytes: Bytes) -> Result<impl warp::Reply, warp::Rejection
     let end_data = bytes.len() - 48;
     let data = &bytes[begin_data..end_data];
     let path = format!("{FOLDER_NAME}\\{name}");
-    fs::write(path, data).unwrap();
+    fs::write(&path, data).unwrap();
+    info!(path, bytes = data.len(), "saved photo");
     Ok(warp::reply())
 }
 
 #[tokio::main]
 async fn main() {
+    init_logging();
     let port = 3030;
     let my_local_ip = local_ip().unwrap();
 
@@ -56,6 +60,7 @@ async fn main() {
     
     let routes = hello.or(upload_photo);
     let addr = SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), port);
+    info!("photo server listening at {my_local_ip}:{port}");
 
     warp::serve(routes)
         .run(addr)
@@ -0,0 +1,228 @@
+use std::{convert::Infallible, net::SocketAddr, sync::{atomic::Ordering, Arc}};
+
+use bytes::Bytes;
+use chrono::{DateTime, Local};
+use msgs::latency::LatencyStats;
+use serde::Serialize;
+use tracing::{info, warn};
+use warp::{http::StatusCode, reject::{Reject, Rejection}, reply::Reply, Filter};
+
+use crate::{broadcast_msg::BroadcastMsg, config::Config, metrics::render_metrics, server_context::ServerContext};
+
+const MAX_LOG_FILTER_LEN: u64 = 4096;
+
+#[derive(Serialize)]
+struct SessionView {
+    session_id: u16,
+    device_id: u32,
+    client_type: Option<String>,
+    peer_addr: String,
+    connected_at: String,
+    bytes_in: u64,
+    bytes_out: u64,
+    rate_limited: u64,
+    latency: Option<LatencyStats>,
+    /// Messages waiting in the outbound queue.
+    queue_depth: usize,
+    /// Messages the outbound queue dropped or coalesced because it was full.
+    dropped: u64,
+    coalesced: u64,
+}
+
+#[derive(Serialize)]
+struct FactView {
+    room: u8,
+    creator_id: u16,
+    index: u16,
+    version: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct OwnerView {
+    room: u8,
+    creator_id: u16,
+    index: u16,
+    owner_id: u16,
+}
+
+#[derive(Serialize)]
+struct ModelView {
+    facts: Vec<FactView>,
+    owners: Vec<OwnerView>,
+}
+
+/// Read only views plus kicking for operators, serves
+/// - `GET /sessions`
+/// - `GET /model`
+/// - `POST /sessions/<session id>/kick`
+/// - `GET /metrics` in the prometheus text format
+/// - `GET /log-level` and `PUT /log-level` with a filter like `info,server=debug` as the body
+///
+/// With an admin token every request needs an `Authorization: Bearer <token>` header, anything else gets a 401.
+pub fn spawn_admin_api(addr: SocketAddr, context: ServerContext) -> anyhow::Result<()> {
+    let authorized = with_admin_token(context.config.clone());
+
+    let sessions_route = warp::path!("sessions")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(sessions_handler);
+
+    let model_route = warp::path!("model")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(model_handler);
+
+    let kick_route = warp::path!("sessions" / u16 / "kick")
+        .and(warp::post())
+        .and(with_context(context.clone()))
+        .and_then(kick_handler);
+
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(metrics_handler);
+
+    let get_log_level_route = warp::path!("log-level")
+        .and(warp::get())
+        .and(with_context(context.clone()))
+        .and_then(get_log_level_handler);
+
+    let set_log_level_route = warp::path!("log-level")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_LOG_FILTER_LEN))
+        .and(warp::body::bytes())
+        .and(with_context(context))
+        .and_then(set_log_level_handler);
+
+    let routes = authorized
+        .and(sessions_route
+            .or(model_route)
+            .or(kick_route)
+            .or(metrics_route)
+            .or(get_log_level_route)
+            .or(set_log_level_route))
+        .recover(unauthorized_handler);
+
+    let (addr, server) = warp::serve(routes).try_bind_ephemeral(addr)?;
+    info!("admin api listening on {addr}");
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+fn with_admin_token(config: Arc<Config>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let config = config.clone();
+            async move {
+                let Some(admin_token) = &config.admin_token else { return Ok(()) };
+                let token = authorization.as_deref().and_then(|authorization| authorization.strip_prefix("Bearer "));
+                match token {
+                    Some(token) if tokens_match(admin_token, token.as_bytes()) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Compares in constant time.
+fn tokens_match(expected: &[u8], token: &[u8]) -> bool {
+    expected.len() == token.len() && expected.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn unauthorized_handler(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_none() {
+        return Err(rejection);
+    }
+    let reply = warp::reply::with_status("missing or wrong admin token", StatusCode::UNAUTHORIZED);
+    Ok(warp::reply::with_header(reply, "www-authenticate", "Bearer"))
+}
+
+fn with_context(context: ServerContext) -> impl Filter<Extract = (ServerContext,), Error = Infallible> + Clone {
+    warp::any().map(move || context.clone())
+}
+
+async fn sessions_handler(context: ServerContext) -> Result<impl Reply, Infallible> {
+    let sessions = context.hub.sessions_queue_stats().into_iter()
+        .map(|(session_id, info, queue_stats)| SessionView {
+            session_id,
+            device_id: info.device_id,
+            client_type: info.client_type.lock().unwrap().map(|client_type| format!("{client_type:?}")),
+            peer_addr: info.peer_addr.to_string(),
+            connected_at: DateTime::<Local>::from(info.connected_at).to_rfc3339(),
+            bytes_in: info.bytes_in.load(Ordering::Relaxed),
+            bytes_out: info.bytes_out.load(Ordering::Relaxed),
+            rate_limited: info.rate_limited.load(Ordering::Relaxed),
+            latency: *info.latency.lock().unwrap(),
+            queue_depth: queue_stats.depth,
+            dropped: queue_stats.dropped,
+            coalesced: queue_stats.coalesced,
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&sessions))
+}
+
+async fn model_handler(context: ServerContext) -> Result<impl Reply, Infallible> {
+    let lock = context.shared_data.read().await;
+    let mut facts = lock.model.facts.iter()
+        .map(|(&(room, creator_id, index), fact)| FactView {
+            room,
+            creator_id,
+            index,
+            version: fact.version,
+            data: fact.data.to_vec(),
+        })
+        .collect::<Vec<_>>();
+    facts.sort_by_key(|fact| (fact.room, fact.creator_id, fact.index));
+    let mut owners = lock.data_owners.iter()
+        .map(|(&(room, creator_id, index), &owner_id)| OwnerView {
+            room,
+            creator_id,
+            index,
+            owner_id,
+        })
+        .collect::<Vec<_>>();
+    owners.sort_by_key(|owner| (owner.room, owner.creator_id, owner.index));
+    Ok(warp::reply::json(&ModelView { facts, owners }))
+}
+
+async fn kick_handler(session_id: u16, context: ServerContext) -> Result<impl Reply, Infallible> {
+    if context.hub.session_info(session_id).is_none() {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    info!(session_id, "kicking on behalf of the admin api");
+    context.hub.send(BroadcastMsg::Kick (session_id));
+    Ok(StatusCode::OK)
+}
+
+async fn metrics_handler(context: ServerContext) -> Result<impl Reply, Infallible> {
+    let body = render_metrics(&context).await;
+    Ok(warp::reply::with_header(body, "content-type", "text/plain; version=0.0.4"))
+}
+
+async fn get_log_level_handler(context: ServerContext) -> Result<impl Reply, Infallible> {
+    Ok(context.log_level.current())
+}
+
+async fn set_log_level_handler(body: Bytes, context: ServerContext) -> Result<impl Reply, Infallible> {
+    let Ok(directives) = std::str::from_utf8(&body) else {
+        return Ok(warp::reply::with_status("filter is not valid utf-8".to_string(), StatusCode::BAD_REQUEST));
+    };
+    match context.log_level.set(directives.trim()) {
+        Ok(()) => {
+            let current = context.log_level.current();
+            info!(filter = %current, "log filter changed on behalf of the admin api");
+            Ok(warp::reply::with_status(current, StatusCode::OK))
+        }
+        Err(e) => {
+            warn!("rejected log filter from the admin api: {e}");
+            Ok(warp::reply::with_status(format!("{e}"), StatusCode::BAD_REQUEST))
+        }
+    }
+}
@@ -1,86 +1,124 @@
-use std::{fs::File, io::Write, net::SocketAddr, sync::Arc, time::SystemTime};
+use std::{collections::HashSet, fs::File, io::{ErrorKind, Write}, net::SocketAddr, sync::{atomic::Ordering, Arc}, time::Duration};
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
-use chrono::Local;
-use msgs::{client_server_msg::{Address, ClientServerMsg}, client_type::ClientType, dequeue::dequeue_msg, model::SharedData, network_version::NETWORK_VERSION_NUMBER, server_client_msg::ServerClientMsg};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::{broadcast, RwLock}};
+use msgs::{auth::{verify_auth_response, MAC_LEN, NONCE_LEN}, client_server_msg::{Address, ClientServerMsg}, dequeue::{dequeue_frame, MIN_FRAME_LEN}, heartbeat::{Heartbeat, HeartbeatAction}, network_version::{format_network_version, NETWORK_VERSION_NUMBER}, relay_stream::BoxedRelayStream, resume::{ResumeToken, NO_RESUME_TOKEN, RESUME_REQUEST_LEN}, server_client_msg::{DenyReason, RejectReason, ServerClientMsg, SessionEntry}, udp_channel::UdpToken};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt, WriteHalf}, net::TcpStream, runtime::Handle, task::JoinHandle, time::Instant};
+use tracing::{field, info, info_span, warn, Instrument, Span};
 
-use crate::broadcast_msg::BroadcastMsg;
+use crate::{broadcast_msg::BroadcastMsg, latency::{record_probe_echo, LatencyProber}, outbound_queue::{CloseReason, OutboundQueue}, ownership::{answer_claim, claim, force_claim, release_all, release_data, release_removed}, permissions::check_permission, rate_limit::{admit, SessionRateLimiter}, resume_registry::{ParkedSession, Resumed}, server_context::ServerContext, session_ids::SessionIdLease, session_hub::SessionInfo};
+
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// For the initial message and the resume request, the auth response has its own timeout.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection whose transport is not set up yet.
+pub enum Incoming {
+    Tcp (TcpStream),
+    /// Already bridged to a byte stream, by the websocket endpoint for example.
+    Stream (BoxedRelayStream),
+}
 
 pub struct ClientDb {
-    pub session_id_counter: u16,
+    /// Session ids get reused, log files are told apart by the number of the connection.
+    connection_counter: u64,
+    client_processes: Vec<JoinHandle<()>>,
 }
 
 impl ClientDb {
     pub fn new() -> ClientDb {
         ClientDb {
-            session_id_counter: 0,
+            connection_counter: 0,
+            client_processes: Vec::new(),
         }
     }
 
-    pub async fn new_client(&mut self, socket: TcpStream, addr: SocketAddr, tx: broadcast::Sender<BroadcastMsg>, log_folder_path: Option<&str>, server_start_time: SystemTime, shared_data: Arc<RwLock<SharedData>>) {
-        socket.set_nodelay(true).unwrap();
-        let session_id = self.session_id_counter;
-        self.session_id_counter += 1;
-
-        let mut log_file = None;
-        if let Some(path) = log_folder_path {
-            let file_path = format!("{path}/{session_id}.muco_log");
-            log_file = Some(File::create_new(file_path).unwrap());
+    /// Waits for the client processes to end, whatever is still running after `timeout` gets aborted.
+    pub async fn shutdown(&mut self, timeout: Duration) {
+        let client_processes = std::mem::take(&mut self.client_processes);
+        let abort_handles = client_processes.iter().map(|client_process| client_process.abort_handle()).collect::<Vec<_>>();
+        let all_ended = async {
+            for client_process in client_processes {
+                let _ = client_process.await;
+            }
+        };
+        if tokio::time::timeout(timeout, all_ended).await.is_err() {
+            warn!("aborting client processes that did not end in time");
+            for abort_handle in abort_handles {
+                abort_handle.abort();
+            }
         }
-        spawn_client_process(socket, tx, session_id, server_start_time, log_file, shared_data);
-        print_message_preamble_no_device_id(session_id);
-        println!("accepted new connection from {addr}");
     }
-}
 
-pub fn print_message_preamble(session_id: u16, device_id: u32) {
-    print_message_preamble_no_device_id(session_id);
-    print!("{device_id} ");
+    pub async fn new_client(&mut self, socket: Incoming, addr: SocketAddr, log_folder_path: Option<&str>, context: ServerContext) {
+        let connection = self.connection_counter;
+        self.connection_counter += 1;
+        info!(peer_addr = %addr, "accepted new connection");
+        self.client_processes.retain(|client_process| !client_process.is_finished());
+        self.client_processes.push(spawn_client_process(socket, addr, connection, log_folder_path.map(str::to_owned), context));
+    }
 }
 
-pub fn print_timestamp() {
-    let date = Local::now();
-    print!("{} ", date.format("%Y-%m-%d %H:%M:%S"));
+pub enum WriterExit {
+    Closed (CloseReason),
+    Error (std::io::Error),
 }
 
-pub fn print_message_preamble_no_device_id(session_id: u16) {
-    print_timestamp();
-    print!("{session_id} ");
+/// Owns the write half of a session's socket and drains its outbound queue into it.
+pub fn spawn_writer_process(mut writer: WriteHalf<BoxedRelayStream>, queue: Arc<OutboundQueue>, info: Arc<SessionInfo>) -> JoinHandle<WriterExit> {
+    tokio::spawn(async move {
+        loop {
+            let bytes = match queue.pop().await {
+                Ok(bytes) => bytes,
+                Err(CloseReason::Shutdown) => {
+                    let _ = writer.shutdown().await;
+                    return WriterExit::Closed (CloseReason::Shutdown);
+                }
+                Err(reason) => return WriterExit::Closed (reason),
+            };
+            if let Err(e) = writer.write_all(&bytes).await {
+                return WriterExit::Error (e);
+            }
+            info.bytes_out.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        }
+    })
 }
 
-
-pub fn spawn_client_process(mut socket: TcpStream, tx: broadcast::Sender<BroadcastMsg>, session_id: u16, server_start_time: SystemTime, mut log_file: Option<File>, shared_data: Arc<RwLock<SharedData>>) {
+pub fn spawn_client_process(socket: Incoming, addr: SocketAddr, connection: u64, log_folder_path: Option<String>, context: ServerContext) -> JoinHandle<()> {
+    // everything logged while serving the connection carries these fields
+    let span = info_span!("session", session_id = field::Empty, device_id = field::Empty, peer_addr = %addr);
     tokio::spawn(async move {
+        let Some(mut socket) = open_stream(socket, &context).await else { return };
         let mut static_buffer = [0; 1024];
         let mut input_buffer = Vec::new();
+        let handshake_deadline = Instant::now() + HANDSHAKE_TIMEOUT;
 
         let network_version_len = NETWORK_VERSION_NUMBER.len();
         let device_id_len = 4;
         let initial_message_len = network_version_len + device_id_len;
-        while input_buffer.len() < initial_message_len {
-            let result = socket.read(&mut static_buffer).await;
-            let len = match result {
-                Ok(len) => len,
-                Err(e) => {
-                    print_message_preamble_no_device_id(session_id);
-                    println!("error while reading from socker: {e}");
-                    return;
-                }
-            };
-            if len == 0 {
-                print_message_preamble_no_device_id(session_id);
-                println!("client died");
+        match tokio::time::timeout_at(handshake_deadline, fill_input_buffer(&mut socket, &mut input_buffer, initial_message_len)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                info!("client died");
+                return;
+            }
+            Ok(Err(e)) => {
+                warn!("error while reading from socket: {e}");
+                return;
+            }
+            Err(_) => {
+                warn!("handshake timed out");
                 return;
             }
-            input_buffer.extend(&static_buffer[..len]);
         }
 
         {
             let network_version_number = &input_buffer[..network_version_len];
-            if network_version_number != NETWORK_VERSION_NUMBER {
-                print_message_preamble_no_device_id(session_id);
-                println!("rejecting client because of network version number, expected: {NETWORK_VERSION_NUMBER:?}, got: {network_version_number:?}");
+            let supported_versions = context.config.client_versions;
+            if !supported_versions.contains(network_version_number) {
+                context.metrics.count_handshake_rejection(network_version_number, supported_versions);
+                warn!("rejecting client because of network version number, supported: {supported_versions}, got: {}", format_network_version(network_version_number));
+                reject_handshake(&mut socket, RejectReason::UnsupportedVersion, &context).await;
                 return;
             }
             input_buffer.drain(..network_version_len);
@@ -88,76 +126,219 @@ pub fn spawn_client_process(mut socket: TcpStream, tx: broadcast::Sender<Broadca
 
         let device_id = LittleEndian::read_u32(&input_buffer);
         input_buffer.drain(..device_id_len);
-        print_message_preamble(session_id, device_id);
-        println!("received initial message");
+        Span::current().record("device_id", device_id);
+        info!("received initial message");
 
-        {
+        let resume_request = match tokio::time::timeout_at(handshake_deadline, read_resume_request(&mut socket, &mut input_buffer)).await {
+            Ok(Ok(resume_request)) => resume_request,
+            Ok(Err(e)) => {
+                warn!("error while reading the resume request: {e}");
+                return;
+            }
+            Err(_) => {
+                warn!("handshake timed out");
+                return;
+            }
+        };
+
+        let mut manager_credential = false;
+        if let Some(secret) = &context.config.secret {
+            let nonce: [u8; NONCE_LEN] = rand::random();
+            let mut output_buffer = Vec::new();
+            ServerClientMsg::AuthChallenge (nonce).pack(&mut output_buffer);
+            if let Err(e) = socket.write_all(&output_buffer).await {
+                warn!("disconnecting because of error while writing to client: {e}");
+                return;
+            }
+            let reject_reason = match tokio::time::timeout(AUTH_TIMEOUT, read_auth_response(&mut socket, &mut input_buffer)).await {
+                Ok(Ok(Some(response))) if context.config.manager_secret.as_ref().is_some_and(|manager_secret| verify_auth_response(manager_secret, &nonce, device_id, &response)) => {
+                    manager_credential = true;
+                    None
+                }
+                Ok(Ok(Some(response))) if verify_auth_response(secret, &nonce, device_id, &response) => None,
+                Ok(Ok(_)) => Some(RejectReason::AuthenticationFailed),
+                Ok(Err(e)) => {
+                    warn!("error while waiting for the auth response: {e}");
+                    return;
+                }
+                Err(_) => Some(RejectReason::AuthenticationTimedOut),
+            };
+            if let Some(reason) = reject_reason {
+                context.metrics.auth_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("rejecting client: {reason}");
+                reject_handshake(&mut socket, reason, &context).await;
+                return;
+            }
+        }
+
+        let mut resumed = None;
+        let mut resumed_session_id = None;
+        if let (Some((session_id, token)), Some(_)) = (resume_request, context.config.resume_grace) {
+            resumed = context.resume_registry.claim(session_id, device_id, &token);
+            if resumed.is_some() {
+                info!(resumed_session_id = session_id, "resuming session");
+                // the resumed id was kept in use while the session was parked
+                resumed_session_id = Some(session_id);
+            }
+            else {
+                info!(resumed_session_id = session_id, "can not resume session, starting a new one");
+            }
+        }
+        let is_resumed = resumed.is_some();
+
+        // only allocated now that the handshake went through, so connections that fail it do not leave quarantined ids behind
+        let Some(session_id) = resumed_session_id.or_else(|| context.session_ids.lock().unwrap().allocate()) else {
+            warn!("refusing connection, no free session id");
+            reject_handshake(&mut socket, RejectReason::ServerFull, &context).await;
+            return;
+        };
+        let lease = SessionIdLease::new(session_id, context.session_ids.clone());
+        Span::current().record("session_id", session_id);
+
+        let mut log_file = log_folder_path.map(|path| File::create_new(format!("{path}/{session_id}_{connection}.muco_log")).unwrap());
+
+        // register before taking the model snapshot so no update between the two gets lost,
+        // anything routed meanwhile waits in the queue until the writer starts after the hello
+        let rate_limiter = SessionRateLimiter::new(context.config.rate_limits.clone(), context.config.rate_limit_kick_after);
+        let info = Arc::new(SessionInfo::new(device_id, addr, manager_credential, rate_limiter));
+        info.bytes_in.fetch_add(initial_message_len as u64, Ordering::Relaxed);
+        if let Some(Resumed::Parked (parked)) = &resumed {
+            *info.client_type.lock().unwrap() = parked.client_type;
+        }
+        let queue = context.hub.register(session_id, info.clone(), is_resumed);
+        if let Some(Resumed::Parked (parked)) = resumed.take() {
+            context.hub.set_rooms(session_id, parked.rooms);
+        }
+        let resume_token = match context.config.resume_grace {
+            Some(_) => context.resume_registry.issue(session_id, device_id),
+            None => NO_RESUME_TOKEN,
+        };
+
+        let udp_token = context.udp_relay.as_ref().map(|udp_relay| udp_relay.open(session_id));
+        let mut cleanup = SessionCleanup {
+            session_id,
+            queue: queue.clone(),
+            info: info.clone(),
+            udp_token,
+            resume_token,
+            lease: Some(lease),
+            resumable: true,
+            context: context.clone(),
+        };
+
+        let hello_sent = {
             let mut output_buffer = Vec::new();
-            let model = shared_data.read().await.model.clone();
+            let sessions = context.hub.sessions_info().into_iter()
+                .filter(|(other_id, _)| *other_id != session_id)
+                .map(|(other_id, other_info)| SessionEntry {
+                    session_id: other_id,
+                    device_id: other_info.device_id,
+                    client_type: *other_info.client_type.lock().unwrap(),
+                })
+                .collect();
+            // everything else reaches the client when it joins the room
+            let model = context.shared_data.read().await.model.in_rooms(&context.hub.rooms(session_id));
             let msg = ServerClientMsg::Hello {
                 session_id,
+                resume_token,
+                supported_versions: context.config.client_versions,
+                sessions,
                 model,
             };
             msg.pack(&mut output_buffer);
+            if let (Some(udp_relay), Some(token)) = (&context.udp_relay, udp_token) {
+                let msg = ServerClientMsg::UdpChannel {
+                    port: udp_relay.port(),
+                    token,
+                };
+                msg.pack(&mut output_buffer);
+            }
             match socket.write_all(&output_buffer).await {
                 Ok(_) => {
+                    info.bytes_out.fetch_add(output_buffer.len() as u64, Ordering::Relaxed);
                     let flush_result = socket.flush().await;
                     match flush_result {
-                        Ok(_) => {},
+                        Ok(_) => true,
                         Err(err) => {
-                            print_message_preamble(session_id, device_id);
-                            println!("error while flushing data: {err}");
-                            return;
+                            warn!("error while flushing data: {err}");
+                            false
                         }
                     }
                 },
                 Err(e) => {
-                    print_message_preamble(session_id, device_id);
-                    println!("disconnecting because of error while writing to client: {e}");
-                    return;
+                    warn!("disconnecting because of error while writing to client: {e}");
+                    false
                 }
             }
+        };
+
+        if hello_sent && is_resumed {
+            let msg = ServerClientMsg::ClientResumed (session_id);
+            let mut output_buffer: Vec<u8> = Vec::new();
+            msg.pack(&mut output_buffer);
+            context.hub.send(BroadcastMsg::Send (Address::Other (session_id), output_buffer));
         }
-        
-        let mut rx = tx.subscribe();
-        let mut should_disconnect = false;
+
+        let (mut reader, writer) = tokio::io::split(socket);
+        let mut writer_process = spawn_writer_process(writer, queue.clone(), info.clone());
+        let mut heartbeat = Heartbeat::new(context.config.idle_timeout);
+        let mut latency_prober = LatencyProber::new(context.config.latency_probe_interval);
+        let mut should_disconnect = !hello_sent;
         while !should_disconnect {
             tokio::select! {
                 biased;
-                result = rx.recv() => {
-                    let broadcast_msg = match result {
-                        Ok(msg) => msg,
-                        Err(e) => {
-                            print_message_preamble(session_id, device_id);
-                            println!("error while receiving: {e}");
-                            print_message_preamble(session_id, device_id);
-                            println!("client disconnected");
-                            break;
+                result = &mut writer_process => {
+                    match result {
+                        Ok(WriterExit::Closed (CloseReason::Kicked)) => {
+                            info!("kicked");
+                            cleanup.resumable = false;
                         }
-                    };
-
-                    process_broadcast_msg(broadcast_msg, session_id, device_id, &mut socket, &mut should_disconnect).await;
+                        Ok(WriterExit::Closed (CloseReason::Overflow)) => warn!("disconnecting because the outbound queue overflowed"),
+                        Ok(WriterExit::Closed (CloseReason::Ended)) => {
+                            info!("outbound queue closed");
+                            cleanup.resumable = false;
+                        }
+                        Ok(WriterExit::Closed (CloseReason::Shutdown)) => {
+                            info!("disconnecting because the server is shutting down");
+                            cleanup.resumable = false;
+                        }
+                        Ok(WriterExit::Closed (CloseReason::Replaced)) => info!("replaced by a resumed connection"),
+                        Ok(WriterExit::Error (e)) => warn!("disconnecting because of error while writing to socket: {e}"),
+                        Err(e) => warn!("writer process failed: {e}"),
+                    }
+                    break;
                 }
-                result = socket.read(&mut static_buffer) => {
+                result = reader.read(&mut static_buffer) => {
                     let len = match result {
                         Ok(len) => len,
                         Err(e) => {
-                            print_message_preamble(session_id, device_id);
-                            println!("error while reading from socket: {e}");
+                            warn!("error while reading from socket: {e}");
                             break;
                         }
                     };
                     if len == 0 {
-                        print_message_preamble(session_id, device_id);
-                        println!("client died");
+                        info!("client died");
                         break;
                     }
+                    heartbeat.received();
+                    info.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
                     input_buffer.extend(&static_buffer[..len]);
 
-                    while let Some((begin, end)) = dequeue_msg(&input_buffer) {
+                    loop {
+                        let (begin, end) = match dequeue_frame(&input_buffer, context.config.max_frame_len) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(e) => {
+                                // the stream can not be trusted anymore, the client may still resume on a fresh connection
+                                context.metrics.framing_errors.fetch_add(1, Ordering::Relaxed);
+                                warn!("disconnecting because of framing error: {e}");
+                                should_disconnect = true;
+                                break;
+                            }
+                        };
                         if let Some(file) = &mut log_file {
                             let since_server_start = std::time::SystemTime::now()
-                                .duration_since(server_start_time)
+                                .duration_since(context.server_start_time)
                                 .expect("Time went backwards").as_millis() as u32;
                             file.write_u32::<LittleEndian>(since_server_start).unwrap();
                             file.write_all(&input_buffer[..end]).unwrap();
@@ -168,111 +349,414 @@ pub fn spawn_client_process(mut socket: TcpStream, tx: broadcast::Sender<Broadca
                         let msg = match decode_result {
                             Ok(msg) => msg,
                             Err(e) => {
-                                print_message_preamble(session_id, device_id);
-                                println!("error while decode msg: {e}");
-                                break;
+                                // the frame boundaries are intact, so only the bad message is skipped and the session goes on
+                                context.metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+                                warn!("skipping message that failed to decode: {e}");
+                                input_buffer.drain(..end);
+                                continue;
                             }
                         };
 
-                        if let Some(response) = process_msg(msg, session_id, &shared_data, &mut should_disconnect).await {
-                            match tx.send(response) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    print_message_preamble(session_id, device_id);
-                                    println!("error while trying to broadcast msg: {e}");
-                                }
-                            }
+                        context.metrics.count_received(msg.variant_name(), end);
+                        if !admit(session_id, &info, msg.variant_name(), end, &context) {
+                            input_buffer.drain(..end);
+                            continue;
+                        }
+
+                        if let Some(response) = process_msg(msg, session_id, &context, &mut should_disconnect).await {
+                            context.hub.send(response);
+                        }
+                        if should_disconnect {
+                            cleanup.resumable = false;
                         }
 
                         input_buffer.drain(..end);
                     }
                 }
+                action = heartbeat.tick() => {
+                    match action {
+                        HeartbeatAction::Idle => {}
+                        HeartbeatAction::Ping => {
+                            let mut output_buffer = Vec::new();
+                            ServerClientMsg::Ping.pack(&mut output_buffer);
+                            queue.push(output_buffer.into(), None);
+                        }
+                        HeartbeatAction::TimedOut => {
+                            warn!("connection timed out");
+                            break;
+                        }
+                    }
+                }
+                probe = latency_prober.tick() => {
+                    queue.push(probe.into(), None);
+                }
             }
         }
-        {
-            let msg = ServerClientMsg::ClientDisconnected (session_id);
-            let mut output_buffer: Vec<u8> = Vec::new();
-            msg.pack(&mut output_buffer);
-            match tx.send(BroadcastMsg::Send(Address::All, output_buffer)) {
-                Ok(_) => {}
-                Err(e) => {
-                    print_message_preamble(session_id, device_id);
-                    println!("error while trying to broadcast exit msg: {e}");
-                }
+        writer_process.abort();
+        if let Some(file) = log_file.take() {
+            if let Err(e) = file.sync_all() {
+                warn!("error while flushing log file: {e}");
             }
         }
-    });
+    }.instrument(span))
 }
 
-pub async fn process_broadcast_msg(broadcast_msg: BroadcastMsg, session_id: u16, device_id: u32, socket: &mut TcpStream, should_disconnect: &mut bool) {
-    match broadcast_msg {
-        BroadcastMsg::Send(address, output_buffer) => {
-            if address.includes(session_id) {
-                match socket.write_all(&output_buffer).await {
-                    Ok(_) => {},
-                    Err(e) => {
-                        print_message_preamble(session_id, device_id);
-                        println!("disconnecting because of error while writing to socket: {e}");
-                        *should_disconnect = true;
-                    }
-                }
+/// Takes the session out of the hub when its process ends, also when it panicked or got aborted,
+/// and then parks the session for the client to resume or ends it for good.
+struct SessionCleanup {
+    session_id: u16,
+    queue: Arc<OutboundQueue>,
+    info: Arc<SessionInfo>,
+    udp_token: Option<UdpToken>,
+    resume_token: ResumeToken,
+    lease: Option<SessionIdLease>,
+    /// Sessions that lost their connection wait for the client to resume them, deliberate ends do not.
+    resumable: bool,
+    context: ServerContext,
+}
+
+impl Drop for SessionCleanup {
+    fn drop(&mut self) {
+        let session_id = self.session_id;
+        let context = self.context.clone();
+        let rooms = context.hub.unregister(session_id, &self.queue).unwrap_or_default();
+        if let (Some(udp_relay), Some(token)) = (&context.udp_relay, &self.udp_token) {
+            udp_relay.close(session_id, token);
+        }
+        let stats = self.queue.stats();
+        if stats.dropped > 0 || stats.coalesced > 0 {
+            info!(dropped = stats.dropped, coalesced = stats.coalesced, "outbound queue dropped or coalesced messages");
+        }
+        let Some(lease) = self.lease.take() else { return };
+        // the session ends on a task of its own, ending it needs the shared data lock
+        let Ok(runtime) = Handle::try_current() else { return };
+        let resume_token = self.resume_token;
+        let Some(resume_grace) = context.config.resume_grace else {
+            runtime.spawn(async move {
+                end_session(session_id, &context).await;
+                drop(lease);
+            }.in_current_span());
+            return;
+        };
+        if !self.resumable {
+            if context.resume_registry.forget(session_id, &resume_token) {
+                runtime.spawn(async move {
+                    end_session(session_id, &context).await;
+                    drop(lease);
+                }.in_current_span());
             }
+            else {
+                lease.hand_over();
+            }
+            return;
+        }
+        let parked = ParkedSession {
+            rooms,
+            client_type: *self.info.client_type.lock().unwrap(),
+        };
+        if !context.resume_registry.park(session_id, &resume_token, parked) {
+            // a resumed connection took over already
+            lease.hand_over();
+            return;
         }
-        BroadcastMsg::Kick(to_kick) => {
-            if to_kick == session_id {
-                *should_disconnect = true;
+        info!("waiting {} seconds for the client to resume the session", resume_grace.as_secs());
+        runtime.spawn(async move {
+            tokio::time::sleep(resume_grace).await;
+            if context.resume_registry.forget(session_id, &resume_token) {
+                info!("session was not resumed");
+                end_session(session_id, &context).await;
             }
+            else {
+                lease.hand_over();
+            }
+        }.in_current_span());
+    }
+}
+
+/// Tells everyone the session is gone for good, removes its ephemeral facts and releases what it owned.
+async fn end_session(session_id: u16, context: &ServerContext) {
+    {
+        let msg = ServerClientMsg::ClientDisconnected (session_id);
+        let mut output_buffer: Vec<u8> = Vec::new();
+        msg.pack(&mut output_buffer);
+        context.hub.send(BroadcastMsg::Send(Address::All, output_buffer));
+    }
+    {
+        let mut lock = context.shared_data.write().await;
+        for (room, creator_id, index) in lock.remove_ephemeral(session_id) {
+            release_removed(&mut lock, (room, creator_id, index), context);
+            let msg = ServerClientMsg::DataRemoved { room, creator_id, index };
+            let mut output_buffer: Vec<u8> = Vec::new();
+            msg.pack(&mut output_buffer);
+            context.hub.send(BroadcastMsg::Send(Address::Room (room), output_buffer));
         }
+        release_all(&mut lock, session_id, context);
     }
 }
 
-pub async fn process_msg<'a>(msg: ClientServerMsg<'a>, session_id: u16, shared_data: &RwLock<SharedData>, should_disconnect: &mut bool) -> Option<BroadcastMsg> {
+/// Runs the tls handshake for tcp connections when the server has a certificate, `None` when it failed.
+async fn open_stream(socket: Incoming, context: &ServerContext) -> Option<BoxedRelayStream> {
+    let socket = match socket {
+        Incoming::Tcp (socket) => socket,
+        Incoming::Stream (stream) => return Some(stream),
+    };
+    socket.set_nodelay(true).unwrap();
+    let Some(tls_acceptor) = &context.tls_acceptor else {
+        return Some(Box::new(socket));
+    };
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => Some(Box::new(stream)),
+        Ok(Err(e)) => {
+            warn!("tls handshake failed: {e}");
+            None
+        }
+        Err(_) => {
+            warn!("tls handshake timed out");
+            None
+        }
+    }
+}
+
+/// The end of the handshake, `None` unless the client asks to resume a session.
+async fn read_resume_request(socket: &mut BoxedRelayStream, input_buffer: &mut Vec<u8>) -> std::io::Result<Option<(u16, ResumeToken)>> {
+    fill_input_buffer(socket, input_buffer, 1).await?;
+    if input_buffer[0] == 0 {
+        input_buffer.drain(..1);
+        return Ok(None);
+    }
+    fill_input_buffer(socket, input_buffer, RESUME_REQUEST_LEN).await?;
+    let session_id = LittleEndian::read_u16(&input_buffer[1..3]);
+    let token = input_buffer[3..RESUME_REQUEST_LEN].try_into().unwrap();
+    input_buffer.drain(..RESUME_REQUEST_LEN);
+    Ok(Some((session_id, token)))
+}
+
+async fn fill_input_buffer(socket: &mut BoxedRelayStream, input_buffer: &mut Vec<u8>, len: usize) -> std::io::Result<()> {
+    let mut static_buffer = [0; 1024];
+    while input_buffer.len() < len {
+        let read_len = socket.read(&mut static_buffer).await?;
+        if read_len == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        input_buffer.extend(&static_buffer[..read_len]);
+    }
+    Ok(())
+}
+
+/// Reads the first message after the handshake, anything but an `AuthResponse` yields `None`.
+async fn read_auth_response(socket: &mut BoxedRelayStream, input_buffer: &mut Vec<u8>) -> std::io::Result<Option<[u8; MAC_LEN]>> {
+    let mut static_buffer = [0; 1024];
+    loop {
+        let frame = match dequeue_frame(input_buffer, MIN_FRAME_LEN + MAC_LEN) {
+            Ok(frame) => frame,
+            // anything longer or shorter can not be an auth response
+            Err(_) => return Ok(None),
+        };
+        if let Some((begin, end)) = frame {
+            let response = match ClientServerMsg::decode(&input_buffer[begin..end], 0) {
+                Ok(ClientServerMsg::AuthResponse (response)) => Some(response),
+                _ => None,
+            };
+            input_buffer.drain(..end);
+            return Ok(response);
+        }
+        let len = socket.read(&mut static_buffer).await?;
+        if len == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        input_buffer.extend(&static_buffer[..len]);
+    }
+}
+
+async fn reject_handshake(socket: &mut BoxedRelayStream, reason: RejectReason, context: &ServerContext) {
+    let mut output_buffer = Vec::new();
+    let msg = ServerClientMsg::HandshakeRejected {
+        reason,
+        supported_versions: context.config.client_versions,
+    };
+    msg.pack(&mut output_buffer);
+    let _ = socket.write_all(&output_buffer).await;
+    let _ = socket.shutdown().await;
+}
+
+pub fn denied(session_id: u16, msg_type: u32, reason: DenyReason) -> BroadcastMsg {
+    let msg = ServerClientMsg::Denied { msg_type, reason };
+    let mut output_buffer: Vec<u8> = Vec::new();
+    msg.pack(&mut output_buffer);
+    BroadcastMsg::Send (Address::Client (session_id), output_buffer)
+}
+
+pub async fn process_msg<'a>(msg: ClientServerMsg<'a>, session_id: u16, context: &ServerContext, should_disconnect: &mut bool) -> Option<BroadcastMsg> {
+    let shared_data = &context.shared_data;
+    if let Some(info) = context.hub.session_info(session_id) {
+        if let Err(reason) = check_permission(&msg, &info, &context.config) {
+            info!(msg_type = msg.variant_name(), %reason, "denied");
+            return Some(denied(session_id, msg.type_index(), reason));
+        }
+    }
     match msg {
         ClientServerMsg::Disconnect => {
             *should_disconnect = true;
             None
         }
-        ClientServerMsg::BinaryMessageTo (address, content) => {
+        ClientServerMsg::UnreliableMessageTo (address, content) if context.udp_relay.is_some() => {
+            context.udp_relay.as_ref().unwrap().relay(session_id, address, content, &context.hub);
+            None
+        }
+        ClientServerMsg::BinaryMessageTo (address, content) | ClientServerMsg::UnreliableMessageTo (address, content) => {
             let msg = ServerClientMsg::InterClient(session_id, content);
             let mut output_buffer: Vec<u8> = Vec::new();
             msg.pack(&mut output_buffer);
             Some(BroadcastMsg::Send (address, output_buffer))
         }
         ClientServerMsg::SetClientType (client_type) => {
-            if client_type != ClientType::Player {
-                None
+            let info = context.hub.session_info(session_id)?;
+            *info.client_type.lock().unwrap() = Some(client_type);
+            let address = Address::Other (session_id);
+            let msg = ServerClientMsg::ClientConnected { session_id, device_id: info.device_id, client_type };
+            let mut output_buffer: Vec<u8> = Vec::new();
+            msg.pack(&mut output_buffer);
+            Some(BroadcastMsg::Send (address, output_buffer))
+        }
+        ClientServerMsg::Kick (to_kick) => Some(BroadcastMsg::Kick (to_kick)),
+        ClientServerMsg::SetData { room, creator_id, index, ephemeral, expected_version, data } => {
+            let mut lock = shared_data.write().await;
+            if let Some(data_owner) = lock.data_owners.get(&(room, creator_id, index)) {
+                if *data_owner != session_id {
+                    return Some(denied(session_id, msg.type_index(), DenyReason::NotOwner));
+                }
+            }
+            if let Some(expected_version) = expected_version {
+                if lock.model.version((room, creator_id, index)) != expected_version {
+                    let (version, data) = match lock.model.facts.get(&(room, creator_id, index)) {
+                        Some(fact) => (fact.version, &fact.data[..]),
+                        None => (0, &[][..]),
+                    };
+                    let msg = ServerClientMsg::SetDataResult { room, creator_id, index, accepted: false, version, data };
+                    let mut output_buffer: Vec<u8> = Vec::new();
+                    msg.pack(&mut output_buffer);
+                    return Some(BroadcastMsg::Send (Address::Client (session_id), output_buffer));
+                }
+            }
+            let version = lock.model.set((room, creator_id, index), data.into());
+            if ephemeral {
+                lock.ephemeral_writers.insert((room, creator_id, index), session_id);
             }
             else {
-                let address = Address::Other (session_id);
-                let msg = ServerClientMsg::ClientConnected (session_id);
-                let mut output_buffer: Vec<u8> = Vec::new();
+                lock.ephemeral_writers.remove(&(room, creator_id, index));
+            }
+            let address = Address::OtherInRoom (room, session_id);
+            let msg = ServerClientMsg::DataNotify { room, creator_id, index, version, data };
+            let mut output_buffer: Vec<u8> = Vec::new();
+            msg.pack(&mut output_buffer);
+            if expected_version.is_none() {
+                return Some(BroadcastMsg::Send (address, output_buffer));
+            }
+            context.hub.send(BroadcastMsg::Send (address, output_buffer));
+            let msg = ServerClientMsg::SetDataResult { room, creator_id, index, accepted: true, version, data };
+            let mut output_buffer: Vec<u8> = Vec::new();
+            msg.pack(&mut output_buffer);
+            Some(BroadcastMsg::Send (Address::Client (session_id), output_buffer))
+        }
+        ClientServerMsg::ClaimData { room, creator_id, index } => claim((room, creator_id, index), session_id, context).await,
+        ClientServerMsg::AnswerClaim { room, creator_id, index, claimant, grant } => {
+            answer_claim((room, creator_id, index), session_id, claimant, grant, context, msg.type_index()).await
+        }
+        ClientServerMsg::ForceClaimData { room, creator_id, index } => {
+            force_claim((room, creator_id, index), session_id, context).await;
+            None
+        }
+        ClientServerMsg::JoinRoom (room) => {
+            if !context.hub.join_room(session_id, room) {
+                return None;
+            }
+            // catch the new member up on everything that was written to the room while it was away
+            let lock = shared_data.read().await;
+            let mut output_buffer: Vec<u8> = Vec::new();
+            for (&(fact_room, creator_id, index), fact) in &lock.model.facts {
+                if fact_room != room {
+                    continue;
+                }
+                let msg = ServerClientMsg::DataNotify { room, creator_id, index, version: fact.version, data: &fact.data };
                 msg.pack(&mut output_buffer);
-                Some(BroadcastMsg::Send (address, output_buffer))
+                if let Some(&owner_id) = lock.data_owners.get(&(room, creator_id, index)) {
+                    let msg = ServerClientMsg::DataOwner { room, creator_id, index, owner_id: Some(owner_id) };
+                    msg.pack(&mut output_buffer);
+                }
             }
+            if output_buffer.is_empty() {
+                return None;
+            }
+            Some(BroadcastMsg::Send (Address::Client (session_id), output_buffer))
         }
-        ClientServerMsg::Kick (to_kick) => Some(BroadcastMsg::Kick (to_kick)),
-        ClientServerMsg::SetData { room, creator_id, index, data } => {
+        ClientServerMsg::LeaveRoom (room) => {
+            context.hub.leave_room(session_id, room);
+            None
+        }
+        ClientServerMsg::Ping => {
+            let mut output_buffer: Vec<u8> = Vec::new();
+            ServerClientMsg::Pong.pack(&mut output_buffer);
+            Some(BroadcastMsg::Send (Address::Client (session_id), output_buffer))
+        }
+        ClientServerMsg::Pong => None,
+        ClientServerMsg::LatencyProbeEcho (timestamp) => {
+            record_probe_echo(session_id, timestamp, context);
+            None
+        }
+        // only meaningful during the handshake
+        ClientServerMsg::AuthResponse (_) => None,
+        ClientServerMsg::ReleaseData { room, creator_id, index } => {
+            release_data((room, creator_id, index), session_id, context, msg.type_index()).await
+        }
+        ClientServerMsg::DeleteData { room, creator_id, index } => {
             let mut lock = shared_data.write().await;
             if let Some(data_owner) = lock.data_owners.get(&(room, creator_id, index)) {
-                if *data_owner != session_id as u16 {
-                    return None;
+                if *data_owner != session_id {
+                    return Some(denied(session_id, msg.type_index(), DenyReason::NotOwner));
                 }
             }
-            lock.model.facts.insert((room, creator_id, index), data.into());
-            let address = Address::Other (session_id);
-            let msg = ServerClientMsg::DataNotify { room, creator_id, index, data };
+            let existed = lock.remove_fact((room, creator_id, index));
+            release_removed(&mut lock, (room, creator_id, index), context);
+            if !existed {
+                return None;
+            }
+            let address = Address::OtherInRoom (room, session_id);
+            let msg = ServerClientMsg::DataRemoved { room, creator_id, index };
             let mut output_buffer: Vec<u8> = Vec::new();
             msg.pack(&mut output_buffer);
             Some(BroadcastMsg::Send (address, output_buffer))
         }
-        ClientServerMsg::ClaimData { room, creator_id, index } => {
+        ClientServerMsg::ClearRoom (room) => {
             let mut lock = shared_data.write().await;
-            lock.data_owners.insert((room, creator_id, index), session_id as u16);
-            let address = Address::Other (session_id);
-            let msg = ServerClientMsg::DataOwner { room, creator_id, index, owner_id: session_id as u16 };
-            let mut output_buffer: Vec<u8> = Vec::new();
-            msg.pack(&mut output_buffer);
-            Some(BroadcastMsg::Send (address, output_buffer))
+            let owned_by_other = lock.data_owners.iter()
+                .any(|(&(fact_room, _, _), &owner_id)| fact_room == room && owner_id != session_id);
+            if owned_by_other {
+                return Some(denied(session_id, msg.type_index(), DenyReason::NotOwner));
+            }
+            let removed = lock.model.facts.keys()
+                .filter(|(fact_room, _, _)| *fact_room == room)
+                .copied()
+                .collect::<Vec<_>>();
+            for key in &removed {
+                lock.remove_fact(*key);
+            }
+            // including keys that are owned or claimed without having a fact
+            let claimed = lock.data_owners.keys()
+                .chain(lock.claim_requests.keys())
+                .filter(|(fact_room, _, _)| *fact_room == room)
+                .copied()
+                .collect::<HashSet<_>>();
+            for key in claimed {
+                release_removed(&mut lock, key, context);
+            }
+            // one message per fact so the outbound queues can coalesce them like single deletes
+            for (room, creator_id, index) in removed {
+                let msg = ServerClientMsg::DataRemoved { room, creator_id, index };
+                let mut output_buffer: Vec<u8> = Vec::new();
+                msg.pack(&mut output_buffer);
+                context.hub.send(BroadcastMsg::Send (Address::OtherInRoom (room, session_id), output_buffer));
+            }
+            None
         }
     }
 }
@@ -0,0 +1,217 @@
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Context};
+use msgs::{auth::secret_from_env, dequeue::DEFAULT_MAX_CLIENT_FRAME_LEN, network_version::{parse_network_version, NetworkVersion, VersionRange}};
+
+use crate::{outbound_queue::OverflowPolicy, rate_limit::{parse_rate_limit_rule, RateLimit}};
+
+pub const USAGE: &str = "usage: server [log] [--idle-timeout <seconds>] [--queue-capacity <messages>] [--overflow-policy drop-oldest|coalesce|disconnect] [--shutdown-timeout <seconds>] [--snapshot <path>] [--snapshot-interval <seconds>] [--restored-owner-grace <seconds>] [--admin-addr <ip:port>] [--admin-token-file <path>] [--secret-file <path>] [--manager-secret-file <path>] [--manager-room <room>]... [--resume-grace <seconds>] [--session-id-quarantine <seconds>] [--min-client-version <a.b.c>] [--max-client-version <a.b.c>] [--tls-cert <pem path> --tls-key <pem path>] [--ws-addr <ip:port>] [--udp-port <port>] [--rate-limit <message type|all>=<messages per second>/<bytes per second>]... [--rate-limit-kick-after <dropped messages>] [--max-frame-len <bytes>] [--claim-timeout <seconds>] [--latency-probe-interval <seconds>]";
+
+pub struct Config {
+    pub enable_logging: bool,
+    pub idle_timeout: Option<Duration>,
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub shutdown_timeout: Duration,
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval: Option<Duration>,
+    pub restored_owner_grace: Duration,
+    pub admin_addr: Option<SocketAddr>,
+    /// Requests to the admin api have to carry this as a bearer token, required unless it only listens on loopback.
+    pub admin_token: Option<Vec<u8>>,
+    /// Clients have to prove they know this before they get a session, `None` accepts everyone.
+    pub secret: Option<Vec<u8>>,
+    /// Sessions have to authenticate with this instead of `secret` to declare themselves a manager.
+    pub manager_secret: Option<Vec<u8>>,
+    /// Rooms in which only managers may own, write or delete facts, requires `manager_secret`.
+    pub manager_rooms: HashSet<u8>,
+    /// How long a session that lost its connection waits for the client to resume it, `None` disables resumption
+    /// and is the default.
+    pub resume_grace: Option<Duration>,
+    /// How long a freed session id is not handed out again.
+    pub session_id_quarantine: Duration,
+    /// Network versions of clients that may connect, both ends default to our own.
+    pub client_versions: VersionRange,
+    /// Pem files of the certificate chain and its private key, the relay only speaks tls when both are set.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Where browser clients connect over websockets, wss when a tls certificate is configured. `None` only accepts tcp clients.
+    pub ws_addr: Option<SocketAddr>,
+    /// Port of the udp side channel for pose updates, `None` relays unreliable messages over tcp.
+    pub udp_port: Option<u16>,
+    /// Token bucket limits of every session by message type, `all` limits the whole traffic of a session.
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// Sessions get kicked once this many of their messages got dropped without ten quiet seconds in between,
+    /// `None` never kicks.
+    pub rate_limit_kick_after: Option<u32>,
+    /// Sessions sending a frame longer than this get disconnected.
+    pub max_frame_len: usize,
+    /// How long an owner has to answer a claim before it is denied.
+    pub claim_timeout: Duration,
+    /// How often every session gets a latency probe, `None` disables probing.
+    pub latency_probe_interval: Option<Duration>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            enable_logging: false,
+            idle_timeout: Some(Duration::from_secs(15)),
+            queue_capacity: 256,
+            overflow_policy: OverflowPolicy::Coalesce,
+            shutdown_timeout: Duration::from_secs(5),
+            snapshot_path: None,
+            snapshot_interval: Some(Duration::from_secs(30)),
+            restored_owner_grace: Duration::from_secs(30),
+            admin_addr: None,
+            admin_token: None,
+            secret: secret_from_env(),
+            manager_secret: None,
+            manager_rooms: HashSet::new(),
+            resume_grace: None,
+            session_id_quarantine: Duration::from_secs(300),
+            client_versions: VersionRange::default(),
+            tls_cert: None,
+            tls_key: None,
+            ws_addr: None,
+            udp_port: None,
+            rate_limits: HashMap::new(),
+            rate_limit_kick_after: Some(200),
+            max_frame_len: DEFAULT_MAX_CLIENT_FRAME_LEN,
+            claim_timeout: Duration::from_secs(5),
+            latency_probe_interval: Some(Duration::from_secs(2)),
+        }
+    }
+
+    /// Parses the arguments listed in `USAGE`, an idle timeout of 0 disables the heartbeat
+    /// and a snapshot interval of 0 only writes the snapshot on shutdown, a resume grace of 0 disables resumption,
+    /// a latency probe interval of 0 disables probing.
+    pub fn from_args(args: impl Iterator<Item = String>) -> anyhow::Result<Config> {
+        let mut config = Config::new();
+        let mut args = args.skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "log" => config.enable_logging = true,
+                "--idle-timeout" => {
+                    let seconds = parse_value::<u64>(&arg, args.next())?;
+                    config.idle_timeout = match seconds {
+                        0 => None,
+                        seconds => Some(Duration::from_secs(seconds)),
+                    };
+                }
+                "--queue-capacity" => config.queue_capacity = parse_value(&arg, args.next())?,
+                "--overflow-policy" => config.overflow_policy = parse_value(&arg, args.next())?,
+                "--shutdown-timeout" => config.shutdown_timeout = Duration::from_secs(parse_value(&arg, args.next())?),
+                "--snapshot" => config.snapshot_path = Some(parse_value(&arg, args.next())?),
+                "--snapshot-interval" => {
+                    let seconds = parse_value::<u64>(&arg, args.next())?;
+                    config.snapshot_interval = match seconds {
+                        0 => None,
+                        seconds => Some(Duration::from_secs(seconds)),
+                    };
+                }
+                "--restored-owner-grace" => config.restored_owner_grace = Duration::from_secs(parse_value(&arg, args.next())?),
+                "--admin-addr" => config.admin_addr = Some(parse_value(&arg, args.next())?),
+                "--admin-token-file" => config.admin_token = Some(read_secret_file(parse_value(&arg, args.next())?)?),
+                "--secret-file" => config.secret = Some(read_secret_file(parse_value(&arg, args.next())?)?),
+                "--manager-secret-file" => config.manager_secret = Some(read_secret_file(parse_value(&arg, args.next())?)?),
+                "--manager-room" => {
+                    config.manager_rooms.insert(parse_value(&arg, args.next())?);
+                }
+                "--resume-grace" => {
+                    let seconds = parse_value::<u64>(&arg, args.next())?;
+                    config.resume_grace = match seconds {
+                        0 => None,
+                        seconds => Some(Duration::from_secs(seconds)),
+                    };
+                }
+                "--session-id-quarantine" => config.session_id_quarantine = Duration::from_secs(parse_value(&arg, args.next())?),
+                "--min-client-version" => config.client_versions.min = parse_version_value(&arg, args.next())?,
+                "--max-client-version" => config.client_versions.max = parse_version_value(&arg, args.next())?,
+                "--tls-cert" => config.tls_cert = Some(parse_value(&arg, args.next())?),
+                "--tls-key" => config.tls_key = Some(parse_value(&arg, args.next())?),
+                "--ws-addr" => config.ws_addr = Some(parse_value(&arg, args.next())?),
+                "--udp-port" => config.udp_port = Some(parse_value(&arg, args.next())?),
+                "--rate-limit" => {
+                    let rule = args.next().with_context(|| format!("missing value for {arg}"))?;
+                    let (msg_type, limit) = parse_rate_limit_rule(&rule)?;
+                    config.rate_limits.insert(msg_type, limit);
+                }
+                "--rate-limit-kick-after" => {
+                    let dropped_messages = parse_value::<u32>(&arg, args.next())?;
+                    config.rate_limit_kick_after = match dropped_messages {
+                        0 => None,
+                        dropped_messages => Some(dropped_messages),
+                    };
+                }
+                "--max-frame-len" => config.max_frame_len = parse_value(&arg, args.next())?,
+                "--claim-timeout" => config.claim_timeout = Duration::from_secs(parse_value(&arg, args.next())?),
+                "--latency-probe-interval" => {
+                    let seconds = parse_value::<u64>(&arg, args.next())?;
+                    config.latency_probe_interval = match seconds {
+                        0 => None,
+                        seconds => Some(Duration::from_secs(seconds)),
+                    };
+                }
+                _ => bail!("unrecognized argument: {arg}"),
+            }
+        }
+        if config.manager_secret.is_some() && config.secret.is_none() {
+            bail!("a manager secret needs a venue secret for everyone else");
+        }
+        if !config.manager_rooms.is_empty() && config.manager_secret.is_none() {
+            bail!("manager rooms need --manager-secret-file, otherwise any client can declare itself a manager");
+        }
+        if config.admin_addr.is_some_and(|admin_addr| !admin_addr.ip().is_loopback()) && config.admin_token.is_none() {
+            bail!("an admin api reachable from other hosts needs --admin-token-file");
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            bail!("tls needs both a certificate and a private key");
+        }
+        if config.client_versions.min > config.client_versions.max {
+            bail!("the minimum client version is above the maximum: {}", config.client_versions);
+        }
+        Ok(config)
+    }
+}
+
+fn read_secret_file(path: PathBuf) -> anyhow::Result<Vec<u8>> {
+    let secret = std::fs::read_to_string(&path).with_context(|| format!("failed to read secret file {}", path.display()))?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        bail!("secret file {} is empty", path.display());
+    }
+    Ok(secret.as_bytes().to_vec())
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: Option<String>) -> anyhow::Result<T> {
+    let value = value.with_context(|| format!("missing value for {arg}"))?;
+    let Ok(parsed) = value.parse() else { bail!("invalid value for {arg}: {value}") };
+    Ok(parsed)
+}
+
+fn parse_version_value(arg: &str, value: Option<String>) -> anyhow::Result<NetworkVersion> {
+    let value = value.with_context(|| format!("missing value for {arg}"))?;
+    parse_network_version(&value).with_context(|| format!("invalid value for {arg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> anyhow::Result<Config> {
+        Config::from_args(["server"].iter().chain(args).map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn resumption_is_off_unless_asked_for() {
+        assert_eq!(from_args(&[]).unwrap().resume_grace, None);
+        assert_eq!(from_args(&["--resume-grace", "20"]).unwrap().resume_grace, Some(Duration::from_secs(20)));
+        assert_eq!(from_args(&["--resume-grace", "0"]).unwrap().resume_grace, None);
+    }
+
+    #[test]
+    fn manager_rooms_need_a_manager_secret() {
+        assert!(from_args(&["--manager-room", "1"]).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::{sync::OnceLock, time::Duration};
+
+use msgs::{client_server_msg::Address, client_type::ClientType, latency::LatencyStats, server_client_msg::ServerClientMsg};
+use tokio::time::{interval, Instant, Interval, MissedTickBehavior};
+
+use crate::{broadcast_msg::BroadcastMsg, server_context::ServerContext};
+
+static PROBE_CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+/// Microseconds on a monotonic clock, only ever compared with another reading of the same clock.
+fn probe_clock() -> u64 {
+    PROBE_CLOCK_START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// Sends a `LatencyProbe` every interval, never ticks when probing is disabled.
+pub struct LatencyProber {
+    interval: Option<Interval>,
+}
+
+impl LatencyProber {
+    pub fn new(probe_interval: Option<Duration>) -> LatencyProber {
+        let interval = probe_interval.map(|probe_interval| {
+            let mut interval = interval(probe_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        LatencyProber {
+            interval,
+        }
+    }
+
+    /// Returns the packed probe once it is due.
+    pub async fn tick(&mut self) -> Vec<u8> {
+        let Some(interval) = &mut self.interval else {
+            return std::future::pending().await;
+        };
+        interval.tick().await;
+        let mut output_buffer = Vec::new();
+        ServerClientMsg::LatencyProbe (probe_clock()).pack(&mut output_buffer);
+        output_buffer
+    }
+}
+
+/// Updates the statistics of the session and reports them to every manager.
+pub fn record_probe_echo(session_id: u16, timestamp: u64, context: &ServerContext) {
+    let Some(info) = context.hub.session_info(session_id) else { return };
+    let Some(rtt) = probe_clock().checked_sub(timestamp) else { return };
+    let rtt_ms = rtt as f32 / 1000.0;
+    let stats = {
+        let mut latency = info.latency.lock().unwrap();
+        match &mut *latency {
+            Some(stats) => stats.record(rtt_ms),
+            None => *latency = Some(LatencyStats::new(rtt_ms)),
+        }
+        latency.unwrap()
+    };
+    let msg = ServerClientMsg::LatencyReport { session_id, stats };
+    let mut output_buffer: Vec<u8> = Vec::new();
+    msg.pack(&mut output_buffer);
+    for (manager_id, manager_info) in context.hub.sessions_info() {
+        if *manager_info.client_type.lock().unwrap() == Some(ClientType::Manager) {
+            context.hub.send(BroadcastMsg::Send (Address::Client (manager_id), output_buffer.clone()));
+        }
+    }
+}
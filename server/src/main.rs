@@ -1,33 +1,63 @@
-use std::{env, fs::create_dir, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc};
+use std::{env, fs::create_dir, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::{Arc, Mutex}};
 
-use client_db::print_timestamp;
-use discoverable_service::register_msdn;
+use admin_api::spawn_admin_api;
+use config::{Config, USAGE};
+use broadcast_msg::BroadcastMsg;
+use discoverable_service::{register_msdn, unregister_msdn};
 use local_ip_address::local_ip;
-use msgs::model::SharedData;
-use tokio::{net::TcpListener, sync::{broadcast, RwLock}};
-use crate::{broadcast_msg::BroadcastMsg, client_db::ClientDb};
+use logging::init_logging;
+use msgs::{client_server_msg::Address, model::SharedData, server_client_msg::ServerClientMsg};
+use metrics::Metrics;
+use resume_registry::ResumeRegistry;
+use session_hub::SessionHub;
+use session_ids::SessionIdAllocator;
+use snapshot::{read_snapshot, spawn_periodic_snapshot, spawn_restored_owner_release, write_snapshot};
+use tls::load_tls_acceptor;
+use tokio::{net::TcpListener, sync::RwLock};
+use tracing::{error, info, warn};
+use udp_relay::{spawn_udp_relay_process, UdpRelay};
+use websocket::spawn_websocket_endpoint;
+use crate::{client_db::{ClientDb, Incoming}, server_context::ServerContext};
 
+mod admin_api;
 mod client_db;
 mod broadcast_msg;
+mod config;
+mod latency;
+mod metrics;
+mod outbound_queue;
+mod ownership;
+mod permissions;
+mod rate_limit;
+mod resume_registry;
+mod server_context;
+mod session_hub;
+mod session_ids;
+mod snapshot;
+mod tls;
+mod udp_relay;
+mod websocket;
 
 #[tokio::main]
 async fn main() {
+    let log_level = init_logging();
     let server_start_time = std::time::SystemTime::now();
     let since_the_epoch = server_start_time
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards").as_secs();
 
-    let mut enable_logging: bool = false;
-    let args: Vec<String> = env::args().collect();
-    if let Some(arg) = args.get(1) {
-        if arg == "log" {
-            enable_logging = true;
+    let config = match Config::from_args(env::args()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{e}");
+            eprintln!("{USAGE}");
+            return;
         }
-    }
+    };
 
     let path = format!("log_{since_the_epoch}");
-    let log_folder_path = if enable_logging {
-        println!("logging enabled");
+    let log_folder_path = if config.enable_logging {
+        info!("logging enabled");
         create_dir(&path).unwrap();
         Some(&path[..])
     }
@@ -35,29 +65,189 @@ async fn main() {
         None
     };
 
+    let snapshot = match &config.snapshot_path {
+        Some(snapshot_path) => match read_snapshot(snapshot_path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("{e:#}");
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => match load_tls_acceptor(cert_path, key_path) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("{e:#}");
+                return;
+            }
+        },
+        _ => None,
+    };
+
     let port = 1302;
     let my_local_ip = local_ip().unwrap();
 
-    let _mdns = register_msdn(my_local_ip, port, "muco-server");
+    let mdns = register_msdn(my_local_ip, port, "muco-server");
 
     let addr = &SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), port);
     let listener = TcpListener::bind(addr).await.unwrap();
     
 
-    print_timestamp();
-    println!("Server Started at ip: {my_local_ip}:{port}");
+    let udp_relay = match config.udp_port {
+        Some(udp_port) => match UdpRelay::bind(udp_port).await {
+            Ok(udp_relay) => Some(Arc::new(udp_relay)),
+            Err(e) => {
+                error!("failed to bind udp port {udp_port}: {e}");
+                return;
+            }
+        },
+        None => None,
+    };
+
+    info!("Server Started at ip: {my_local_ip}:{port}");
+    if let Some(udp_relay) = &udp_relay {
+        info!("relaying unreliable messages over udp port {}", udp_relay.port());
+    }
+    if config.manager_secret.is_none() {
+        warn!("no manager secret configured, any client can declare itself a manager");
+    }
+    let tls_acceptor = tls.map(|(tls_acceptor, fingerprint)| {
+        info!("speaking tls, certificate fingerprint: {fingerprint}");
+        tls_acceptor
+    });
 
     let mut client_db = ClientDb::new();
 
-    let (tx, _) = broadcast::channel::<BroadcastMsg>(100);
+    let metrics = Arc::new(Metrics::new());
+    let hub = Arc::new(SessionHub::new(config.queue_capacity, config.overflow_policy, metrics.clone()));
+    let shutdown_timeout = config.shutdown_timeout;
 
-    let shared_data = Arc::new(RwLock::new(SharedData::new()));
+    let mut restored_owners = Vec::new();
+    let mut session_ids = SessionIdAllocator::new(config.session_id_quarantine, 0);
+    let shared_data = match snapshot {
+        Some(snapshot) => {
+            info!("restored {} facts and {} ownerships from the snapshot", snapshot.shared_data.model.facts.len(), snapshot.shared_data.data_owners.len());
+            session_ids = SessionIdAllocator::new(config.session_id_quarantine, snapshot.next_session_id);
+            restored_owners.extend(snapshot.shared_data.data_owners.iter().map(|(key, owner_id)| (*key, *owner_id)));
+            for (_, owner_id) in &restored_owners {
+                session_ids.reserve(*owner_id);
+            }
+            snapshot.shared_data
+        }
+        None => SharedData::new(),
+    };
+    let shared_data = Arc::new(RwLock::new(shared_data));
+    let snapshot_path = config.snapshot_path.clone();
+    let snapshot_interval = config.snapshot_interval;
+    let restored_owner_grace = config.restored_owner_grace;
+    let admin_addr = config.admin_addr;
+    let ws_addr = config.ws_addr;
+
+    let context = ServerContext {
+        hub: hub.clone(),
+        shared_data,
+        server_start_time,
+        config: Arc::new(config),
+        metrics,
+        resume_registry: Arc::new(ResumeRegistry::new()),
+        session_ids: Arc::new(Mutex::new(session_ids)),
+        tls_acceptor,
+        udp_relay: udp_relay.clone(),
+        log_level,
+    };
+
+    if let Some(udp_relay) = udp_relay {
+        spawn_udp_relay_process(udp_relay, context.clone());
+    }
+
+    if let Some(admin_addr) = admin_addr {
+        if let Err(e) = spawn_admin_api(admin_addr, context.clone()) {
+            error!("failed to start admin api on {admin_addr}: {e}");
+            return;
+        }
+    }
+
+    // kept here even without a websocket endpoint so the accept loop never sees the channel close
+    let (ws_connections, mut ws_incoming) = tokio::sync::mpsc::channel(16);
+    if let Some(ws_addr) = ws_addr {
+        if let Err(e) = spawn_websocket_endpoint(ws_addr, context.tls_acceptor.clone(), ws_connections.clone()) {
+            error!("failed to start websocket endpoint on {ws_addr}: {e}");
+            return;
+        }
+    }
+
+    let periodic_snapshot = match (&snapshot_path, snapshot_interval) {
+        (Some(snapshot_path), Some(period)) => Some(spawn_periodic_snapshot(snapshot_path.clone(), period, context.clone())),
+        _ => None,
+    };
+
+    if !restored_owners.is_empty() {
+        spawn_restored_owner_release(context.clone(), restored_owners, restored_owner_grace);
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        client_db.new_client(socket, addr, tx.clone(), log_folder_path, server_start_time, shared_data.clone()).await;
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, addr) = accepted.unwrap();
+                client_db.new_client(Incoming::Tcp (socket), addr, log_folder_path, context.clone()).await;
+            }
+            Some((stream, addr)) = ws_incoming.recv() => {
+                client_db.new_client(Incoming::Stream (stream), addr, log_folder_path, context.clone()).await;
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    drop(listener);
+    drop(ws_incoming);
+
+    info!("shutting down");
+
+    // a periodic write still in flight would race the final one for the temporary file
+    if let Some(periodic_snapshot) = periodic_snapshot {
+        periodic_snapshot.abort();
+        let _ = periodic_snapshot.await;
     }
 
-    //TODO shut down propperly
-    // mdns.shutdown().unwrap();
+    // taken before the sessions end so the ownerships they hold are part of it
+    if let Some(snapshot_path) = &snapshot_path {
+        match write_snapshot(snapshot_path, &context).await {
+            Ok(()) => {
+                info!("snapshot written to {}", snapshot_path.display());
+            }
+            Err(e) => {
+                error!("failed to write snapshot: {e}");
+            }
+        }
+    }
+
+    let mut output_buffer: Vec<u8> = Vec::new();
+    ServerClientMsg::ServerShutdown.pack(&mut output_buffer);
+    hub.send(BroadcastMsg::Send (Address::All, output_buffer));
+    hub.shutdown();
+
+    client_db.shutdown(shutdown_timeout).await;
+    unregister_msdn(mdns, "muco-server");
+
+    info!("Server stopped");
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c().await.unwrap();
 }
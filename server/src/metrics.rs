@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use msgs::network_version::VersionRange;
+
+use crate::server_context::ServerContext;
+
+#[derive(Default)]
+struct MsgCounters {
+    messages: u64,
+    bytes: u64,
+}
+
+/// Counters the server keeps for the prometheus endpoint, gauges are computed when rendering.
+#[derive(Default)]
+pub struct Metrics {
+    received: Mutex<BTreeMap<&'static str, MsgCounters>>,
+    /// Bucketed, the version itself comes from unauthenticated peers and could make up any number of series.
+    handshake_rejections: Mutex<BTreeMap<&'static str, u64>>,
+    rate_limited: Mutex<BTreeMap<&'static str, u64>>,
+    pub decode_errors: AtomicU64,
+    pub framing_errors: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub outbound_dropped: AtomicU64,
+    pub outbound_coalesced: AtomicU64,
+    pub outbound_overflow_disconnects: AtomicU64,
+    pub rate_limit_kicks: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn count_received(&self, variant_name: &'static str, bytes: usize) {
+        let mut received = self.received.lock().unwrap();
+        let counters = received.entry(variant_name).or_default();
+        counters.messages += 1;
+        counters.bytes += bytes as u64;
+    }
+
+    pub fn count_handshake_rejection(&self, network_version: &[u8], supported_versions: VersionRange) {
+        let bucket = if *network_version < supported_versions.min[..] { "too_old" } else { "too_new" };
+        *self.handshake_rejections.lock().unwrap().entry(bucket).or_default() += 1;
+    }
+
+    pub fn count_rate_limited(&self, variant_name: &'static str) {
+        *self.rate_limited.lock().unwrap().entry(variant_name).or_default() += 1;
+    }
+}
+
+/// Renders every metric in the prometheus text exposition format.
+pub async fn render_metrics(context: &ServerContext) -> String {
+    let metrics = &context.metrics;
+    let mut out = String::new();
+
+    let mut sessions: BTreeMap<String, u64> = BTreeMap::new();
+    for client_type in ["Player", "Manager", "unknown"] {
+        sessions.insert(client_type.to_owned(), 0);
+    }
+    for (_, info) in context.hub.sessions_info() {
+        let client_type = match *info.client_type.lock().unwrap() {
+            Some(client_type) => format!("{client_type:?}"),
+            None => "unknown".to_owned(),
+        };
+        *sessions.entry(client_type).or_default() += 1;
+    }
+    writeln!(out, "# HELP muco_sessions Connected sessions by client type.").unwrap();
+    writeln!(out, "# TYPE muco_sessions gauge").unwrap();
+    for (client_type, count) in &sessions {
+        writeln!(out, "muco_sessions{{client_type=\"{client_type}\"}} {count}").unwrap();
+    }
+
+    {
+        let received = metrics.received.lock().unwrap();
+        writeln!(out, "# HELP muco_received_messages_total Messages received from clients by message type.").unwrap();
+        writeln!(out, "# TYPE muco_received_messages_total counter").unwrap();
+        for (variant_name, counters) in received.iter() {
+            writeln!(out, "muco_received_messages_total{{type=\"{variant_name}\"}} {}", counters.messages).unwrap();
+        }
+        writeln!(out, "# HELP muco_received_bytes_total Bytes received from clients by message type, including framing.").unwrap();
+        writeln!(out, "# TYPE muco_received_bytes_total counter").unwrap();
+        for (variant_name, counters) in received.iter() {
+            writeln!(out, "muco_received_bytes_total{{type=\"{variant_name}\"}} {}", counters.bytes).unwrap();
+        }
+    }
+
+    let counters = [
+        ("muco_decode_errors_total", "Messages from clients that failed to decode.", &metrics.decode_errors),
+        ("muco_framing_errors_total", "Sessions disconnected because a frame length was out of bounds.", &metrics.framing_errors),
+        ("muco_auth_failures_total", "Handshakes rejected because the client did not answer the challenge correctly.", &metrics.auth_failures),
+        ("muco_outbound_dropped_total", "Messages dropped because an outbound queue was full.", &metrics.outbound_dropped),
+        ("muco_outbound_coalesced_total", "Queued fact updates replaced by a newer one.", &metrics.outbound_coalesced),
+        ("muco_outbound_overflow_disconnects_total", "Sessions disconnected because their outbound queue was full.", &metrics.outbound_overflow_disconnects),
+        ("muco_rate_limit_kicks_total", "Sessions kicked because they kept exceeding their rate limits.", &metrics.rate_limit_kicks),
+    ];
+    for (name, help, counter) in counters {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} counter").unwrap();
+        writeln!(out, "{name} {}", counter.load(Ordering::Relaxed)).unwrap();
+    }
+
+    write_handshake_rejections(&mut out, metrics);
+
+    writeln!(out, "# HELP muco_rate_limited_messages_total Messages dropped because their session exceeded its rate limits, by message type.").unwrap();
+    writeln!(out, "# TYPE muco_rate_limited_messages_total counter").unwrap();
+    for (variant_name, count) in metrics.rate_limited.lock().unwrap().iter() {
+        writeln!(out, "muco_rate_limited_messages_total{{type=\"{variant_name}\"}} {count}").unwrap();
+    }
+
+    let session_ids_in_use = context.session_ids.lock().unwrap().in_use_count();
+    writeln!(out, "# HELP muco_session_ids_in_use Session ids held by live and parked sessions.").unwrap();
+    writeln!(out, "# TYPE muco_session_ids_in_use gauge").unwrap();
+    writeln!(out, "muco_session_ids_in_use {session_ids_in_use}").unwrap();
+
+    let (fact_count, fact_bytes) = {
+        let lock = context.shared_data.read().await;
+        (lock.model.facts.len(), lock.model.facts.values().map(|fact| fact.data.len()).sum::<usize>())
+    };
+    writeln!(out, "# HELP muco_facts Facts in the shared model.").unwrap();
+    writeln!(out, "# TYPE muco_facts gauge").unwrap();
+    writeln!(out, "muco_facts {fact_count}").unwrap();
+    writeln!(out, "# HELP muco_fact_bytes Total size of the facts in the shared model.").unwrap();
+    writeln!(out, "# TYPE muco_fact_bytes gauge").unwrap();
+    writeln!(out, "muco_fact_bytes {fact_bytes}").unwrap();
+
+    out
+}
+
+fn write_handshake_rejections(out: &mut String, metrics: &Metrics) {
+    writeln!(out, "# HELP muco_handshake_rejections_total Handshakes rejected because the client's network version was older or newer than the supported ones.").unwrap();
+    writeln!(out, "# TYPE muco_handshake_rejections_total counter").unwrap();
+    for (reason, count) in metrics.handshake_rejections.lock().unwrap().iter() {
+        writeln!(out, "muco_handshake_rejections_total{{reason=\"{reason}\"}} {count}").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_rejections_are_labelled_by_reason() {
+        let metrics = Metrics::new();
+        let supported_versions = VersionRange { min: [0, 0, 5], max: [0, 0, 9] };
+        metrics.count_handshake_rejection(&[0, 0, 1], supported_versions);
+        metrics.count_handshake_rejection(&[0, 0, 2], supported_versions);
+        metrics.count_handshake_rejection(&[9, 9, 9], supported_versions);
+
+        let mut out = String::new();
+        write_handshake_rejections(&mut out, &metrics);
+        assert!(out.contains("muco_handshake_rejections_total{reason=\"too_old\"} 2\n"));
+        assert!(out.contains("muco_handshake_rejections_total{reason=\"too_new\"} 1\n"));
+    }
+}
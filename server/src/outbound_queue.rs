@@ -0,0 +1,251 @@
+use std::{collections::VecDeque, str::FromStr, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use anyhow::bail;
+use msgs::server_client_msg::ServerClientMsg;
+use tokio::sync::Notify;
+
+/// What to do when a session's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    /// Replace the latest queued update or removal of the same fact, otherwise drop the oldest message.
+    Coalesce,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<OverflowPolicy> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => bail!("unknown overflow policy: {s}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    Ended,
+    Kicked,
+    Overflow,
+    /// Unlike the other reasons, already queued messages are still delivered.
+    Shutdown,
+    /// The client resumed the session on a new connection.
+    Replaced,
+}
+
+pub enum PushResult {
+    Queued,
+    Dropped,
+    Coalesced,
+    Disconnected,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+struct QueuedMsg {
+    coalesce_key: Option<(u8, u16, u16)>,
+    bytes: Arc<[u8]>,
+}
+
+struct QueueState {
+    msgs: VecDeque<QueuedMsg>,
+    closed: Option<CloseReason>,
+}
+
+/// Bounded queue between the message router and one session's writer task.
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> OutboundQueue {
+        OutboundQueue {
+            state: Mutex::new(QueueState {
+                msgs: VecDeque::new(),
+                closed: None,
+            }),
+            notify: Notify::new(),
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// The caller works out the coalesce key once per message with `coalesce_key`, not once per recipient.
+    pub fn push(&self, bytes: Arc<[u8]>, coalesce_key: Option<(u8, u16, u16)>) -> PushResult {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_some() {
+            return PushResult::Closed;
+        }
+        let mut result = PushResult::Queued;
+        if state.msgs.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Disconnect => {
+                    state.msgs.clear();
+                    state.closed = Some(CloseReason::Overflow);
+                    self.notify.notify_one();
+                    return PushResult::Disconnected;
+                }
+                OverflowPolicy::Coalesce if coalesce_key.is_some() => {
+                    if let Some(queued) = state.msgs.iter_mut().rev().find(|queued| queued.coalesce_key == coalesce_key) {
+                        queued.bytes = bytes;
+                        self.coalesced.fetch_add(1, Ordering::Relaxed);
+                        return PushResult::Coalesced;
+                    }
+                    state.msgs.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    result = PushResult::Dropped;
+                }
+                OverflowPolicy::DropOldest | OverflowPolicy::Coalesce => {
+                    state.msgs.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    result = PushResult::Dropped;
+                }
+            }
+        }
+        state.msgs.push_back(QueuedMsg { coalesce_key, bytes });
+        self.notify.notify_one();
+        result
+    }
+
+    /// Waits for the next message, or returns why the queue was closed.
+    pub async fn pop(&self) -> Result<Arc<[u8]>, CloseReason> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(msg) = state.msgs.pop_front() {
+                    return Ok(msg.bytes);
+                }
+                if let Some(reason) = state.closed {
+                    return Err(reason);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self, reason: CloseReason) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_none() {
+            if reason != CloseReason::Shutdown {
+                state.msgs.clear();
+            }
+            state.closed = Some(reason);
+        }
+        self.notify.notify_one();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.state.lock().unwrap().msgs.len(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Only a buffer holding a single `DataNotify` or `DataRemoved` can be coalesced, a newer value or removal of
+/// the same fact supersedes it.
+pub fn coalesce_key(bytes: &[u8]) -> Option<(u8, u16, u16)> {
+    let (begin, end) = msgs::dequeue::dequeue_msg(bytes)?;
+    if end != bytes.len() {
+        return None;
+    }
+    match ServerClientMsg::decode(&bytes[begin..end]) {
+        Ok(ServerClientMsg::DataNotify { room, creator_id, index, .. }) => Some((room, creator_id, index)),
+        Ok(ServerClientMsg::DataRemoved { room, creator_id, index }) => Some((room, creator_id, index)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_notify(index: u16, value: u8) -> Arc<[u8]> {
+        let mut output_buffer = Vec::new();
+        ServerClientMsg::DataNotify { room: 0, creator_id: 1, index, version: 1, data: &[value] }.pack(&mut output_buffer);
+        output_buffer.into()
+    }
+
+    fn ping() -> Arc<[u8]> {
+        let mut output_buffer = Vec::new();
+        ServerClientMsg::Ping.pack(&mut output_buffer);
+        output_buffer.into()
+    }
+
+    fn push(queue: &OutboundQueue, bytes: Arc<[u8]>) -> PushResult {
+        let coalesce_key = coalesce_key(&bytes);
+        queue.push(bytes, coalesce_key)
+    }
+
+    fn queued(queue: &OutboundQueue) -> Vec<Arc<[u8]>> {
+        queue.state.lock().unwrap().msgs.iter().map(|queued| queued.bytes.clone()).collect()
+    }
+
+    #[test]
+    fn drop_oldest_drops_the_front_when_full() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::DropOldest);
+        assert!(matches!(push(&queue, data_notify(0, 0)), PushResult::Queued));
+        assert!(matches!(push(&queue, data_notify(1, 0)), PushResult::Queued));
+        assert!(matches!(push(&queue, data_notify(2, 0)), PushResult::Dropped));
+        assert_eq!(queued(&queue), vec![data_notify(1, 0), data_notify(2, 0)]);
+        let stats = queue.stats();
+        assert_eq!((stats.depth, stats.dropped, stats.coalesced), (2, 1, 0));
+    }
+
+    #[test]
+    fn coalesce_replaces_the_queued_update_of_the_same_fact() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::Coalesce);
+        push(&queue, data_notify(0, 0));
+        push(&queue, data_notify(1, 0));
+        assert!(matches!(push(&queue, data_notify(0, 7)), PushResult::Coalesced));
+        assert_eq!(queued(&queue), vec![data_notify(0, 7), data_notify(1, 0)]);
+        let stats = queue.stats();
+        assert_eq!((stats.depth, stats.dropped, stats.coalesced), (2, 0, 1));
+    }
+
+    #[test]
+    fn coalesce_drops_the_oldest_without_a_match() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::Coalesce);
+        push(&queue, data_notify(0, 0));
+        push(&queue, data_notify(1, 0));
+        assert!(matches!(push(&queue, data_notify(2, 0)), PushResult::Dropped));
+        assert!(matches!(push(&queue, ping()), PushResult::Dropped));
+        assert_eq!(queued(&queue), vec![data_notify(2, 0), ping()]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue_when_full() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::Disconnect);
+        push(&queue, ping());
+        assert!(matches!(push(&queue, ping()), PushResult::Disconnected));
+        assert!(matches!(push(&queue, ping()), PushResult::Closed));
+        assert_eq!(queue.pop().await, Err(CloseReason::Overflow));
+    }
+
+    #[tokio::test]
+    async fn shutdown_delivers_what_is_queued() {
+        let queue = OutboundQueue::new(4, OverflowPolicy::DropOldest);
+        push(&queue, ping());
+        queue.close(CloseReason::Shutdown);
+        assert_eq!(queue.pop().await, Ok(ping()));
+        assert_eq!(queue.pop().await, Err(CloseReason::Shutdown));
+    }
+}
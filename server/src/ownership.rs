@@ -0,0 +1,150 @@
+use msgs::{client_server_msg::Address, model::{ClaimRequest, SharedData}, server_client_msg::{DenyReason, OwnershipEvent, ServerClientMsg}};
+use tracing::{info, Instrument};
+
+use crate::{broadcast_msg::BroadcastMsg, client_db::denied, server_context::ServerContext};
+
+type FactKey = (u8, u16, u16);
+
+fn ownership_event((room, creator_id, index): FactKey, event: OwnershipEvent, session_id: u16) -> BroadcastMsg {
+    let msg = ServerClientMsg::Ownership { room, creator_id, index, event, session_id };
+    let mut output_buffer: Vec<u8> = Vec::new();
+    msg.pack(&mut output_buffer);
+    BroadcastMsg::Send (Address::Room (room), output_buffer)
+}
+
+fn data_owner((room, creator_id, index): FactKey, owner_id: Option<u16>) -> BroadcastMsg {
+    let msg = ServerClientMsg::DataOwner { room, creator_id, index, owner_id };
+    let mut output_buffer: Vec<u8> = Vec::new();
+    msg.pack(&mut output_buffer);
+    BroadcastMsg::Send (Address::Room (room), output_buffer)
+}
+
+/// Makes `owner_id` the owner, a claim that was still pending is denied.
+fn grant(lock: &mut SharedData, key: FactKey, owner_id: u16, context: &ServerContext) {
+    if let Some(request) = lock.claim_requests.remove(&key) {
+        if request.claimant != owner_id {
+            context.hub.send(ownership_event(key, OwnershipEvent::Denied, request.claimant));
+        }
+    }
+    lock.data_owners.insert(key, owner_id);
+    context.hub.send(data_owner(key, Some(owner_id)));
+    context.hub.send(ownership_event(key, OwnershipEvent::Granted, owner_id));
+}
+
+/// Drops the ownership and hands the fact to the session whose claim was pending, if any.
+pub fn release(lock: &mut SharedData, key: FactKey, owner_id: u16, context: &ServerContext) {
+    lock.data_owners.remove(&key);
+    context.hub.send(ownership_event(key, OwnershipEvent::Released, owner_id));
+    match lock.claim_requests.get(&key) {
+        Some(request) => {
+            let claimant = request.claimant;
+            grant(lock, key, claimant, context);
+        }
+        None => context.hub.send(data_owner(key, None)),
+    }
+}
+
+/// Free facts and facts whose owner has no session are granted right away,
+/// owned ones are asked of their owner, who has `claim_timeout` to answer.
+pub async fn claim(key: FactKey, claimant: u16, context: &ServerContext) -> Option<BroadcastMsg> {
+    let mut lock = context.shared_data.write().await;
+    let owner_id = match lock.data_owners.get(&key) {
+        Some(&owner_id) if owner_id != claimant => owner_id,
+        _ => {
+            grant(&mut lock, key, claimant, context);
+            return None;
+        }
+    };
+    let owner_connected = context.hub.session_info(owner_id).is_some();
+    if !owner_connected && !context.resume_registry.is_parked(owner_id) {
+        // restored from a snapshot or already gone, nobody is left to answer
+        info!(owner_id, claimant, ?key, "granting the claim, the owner has no session");
+        grant(&mut lock, key, claimant, context);
+        return None;
+    }
+    if lock.claim_requests.contains_key(&key) {
+        return Some(ownership_event(key, OwnershipEvent::Denied, claimant));
+    }
+    let id = lock.next_claim_request_id;
+    lock.next_claim_request_id += 1;
+    lock.claim_requests.insert(key, ClaimRequest { id, claimant });
+    drop(lock);
+
+    let (room, creator_id, index) = key;
+    if owner_connected && !context.hub.recipients(Address::Room (room)).contains(&owner_id) {
+        // the owner left the room and would not hear about the claim otherwise
+        let msg = ServerClientMsg::Ownership { room, creator_id, index, event: OwnershipEvent::Requested, session_id: claimant };
+        let mut output_buffer: Vec<u8> = Vec::new();
+        msg.pack(&mut output_buffer);
+        context.hub.send(BroadcastMsg::Send (Address::Client (owner_id), output_buffer));
+    }
+
+    let context = context.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(context.config.claim_timeout).await;
+        let mut lock = context.shared_data.write().await;
+        if lock.claim_requests.get(&key).map(|request| request.id) != Some(id) {
+            return;
+        }
+        lock.claim_requests.remove(&key);
+        info!(owner_id, claimant, ?key, "owner did not answer the claim in time");
+        context.hub.send(ownership_event(key, OwnershipEvent::Denied, claimant));
+    }.in_current_span());
+    Some(ownership_event(key, OwnershipEvent::Requested, claimant))
+}
+
+/// Answers to claims that are not pending anymore are ignored.
+pub async fn answer_claim(key: FactKey, owner_id: u16, claimant: u16, grant_claim: bool, context: &ServerContext, msg_type: u32) -> Option<BroadcastMsg> {
+    let mut lock = context.shared_data.write().await;
+    if lock.data_owners.get(&key) != Some(&owner_id) {
+        return Some(denied(owner_id, msg_type, DenyReason::NotOwner));
+    }
+    if lock.claim_requests.get(&key).map(|request| request.claimant) != Some(claimant) {
+        return None;
+    }
+    if grant_claim {
+        grant(&mut lock, key, claimant, context);
+        None
+    }
+    else {
+        lock.claim_requests.remove(&key);
+        Some(ownership_event(key, OwnershipEvent::Denied, claimant))
+    }
+}
+
+pub async fn force_claim(key: FactKey, session_id: u16, context: &ServerContext) {
+    let mut lock = context.shared_data.write().await;
+    grant(&mut lock, key, session_id, context);
+}
+
+pub async fn release_data(key: FactKey, session_id: u16, context: &ServerContext, msg_type: u32) -> Option<BroadcastMsg> {
+    let mut lock = context.shared_data.write().await;
+    if lock.data_owners.get(&key) != Some(&session_id) {
+        return Some(denied(session_id, msg_type, DenyReason::NotOwner));
+    }
+    release(&mut lock, key, session_id, context);
+    None
+}
+
+/// Drops the ownership of a removed fact, a claim that was still pending is denied.
+pub fn release_removed(lock: &mut SharedData, key: FactKey, context: &ServerContext) {
+    if let Some(request) = lock.claim_requests.remove(&key) {
+        context.hub.send(ownership_event(key, OwnershipEvent::Denied, request.claimant));
+    }
+    if let Some(owner_id) = lock.data_owners.remove(&key) {
+        context.hub.send(ownership_event(key, OwnershipEvent::Released, owner_id));
+        context.hub.send(data_owner(key, None));
+    }
+}
+
+/// Releases everything the ended session owned and forgets its pending claims.
+pub fn release_all(lock: &mut SharedData, session_id: u16, context: &ServerContext) {
+    lock.claim_requests.retain(|_, request| request.claimant != session_id);
+    let owned = lock.data_owners.iter()
+        .filter(|(_, owner_id)| **owner_id == session_id)
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    for key in owned {
+        release(lock, key, session_id, context);
+    }
+}
@@ -0,0 +1,20 @@
+use msgs::{client_server_msg::ClientServerMsg, client_type::ClientType, server_client_msg::DenyReason};
+
+use crate::{config::Config, session_hub::SessionInfo};
+
+/// Checks a message against what the client type the sender declared allows, before `process_msg` acts on it.
+/// Ownership of single facts is checked where the fact is looked up.
+pub fn check_permission(msg: &ClientServerMsg, info: &SessionInfo, config: &Config) -> Result<(), DenyReason> {
+    let is_manager = *info.client_type.lock().unwrap() == Some(ClientType::Manager);
+    match msg {
+        ClientServerMsg::Kick (_) | ClientServerMsg::ForceClaimData { .. } if !is_manager => Err(DenyReason::ManagerOnly),
+        ClientServerMsg::SetClientType (ClientType::Manager) if config.manager_secret.is_some() && !info.manager_credential => {
+            Err(DenyReason::ManagerCredentialRequired)
+        }
+        ClientServerMsg::ClaimData { room, .. }
+        | ClientServerMsg::SetData { room, .. }
+        | ClientServerMsg::DeleteData { room, .. }
+        | ClientServerMsg::ClearRoom (room) if config.manager_rooms.contains(room) && !is_manager => Err(DenyReason::RoomRestricted),
+        _ => Ok(()),
+    }
+}
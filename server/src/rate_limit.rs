@@ -0,0 +1,268 @@
+use std::{collections::HashMap, str::FromStr, sync::atomic::Ordering, time::{Duration, Instant}};
+
+use anyhow::{bail, Context};
+use msgs::client_server_msg::ClientServerMsg;
+use tracing::warn;
+
+use crate::{broadcast_msg::BroadcastMsg, server_context::ServerContext, session_hub::SessionInfo};
+
+/// Applies to the whole traffic of a session instead of a single message type.
+pub const ALL_MESSAGES: &str = "all";
+
+/// A streak of dropped messages ends after this long without another drop.
+const DROP_STREAK_RESET: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub messages_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+/// Parses `<messages per second>/<bytes per second>`.
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<RateLimit> {
+        let Some((messages_per_sec, bytes_per_sec)) = text.split_once('/') else {
+            bail!("a rate limit has to look like <messages per second>/<bytes per second>, got {text}");
+        };
+        let limit = RateLimit {
+            messages_per_sec: messages_per_sec.parse().with_context(|| format!("invalid messages per second in {text}"))?,
+            bytes_per_sec: bytes_per_sec.parse().with_context(|| format!("invalid bytes per second in {text}"))?,
+        };
+        if !(limit.messages_per_sec > 0.0 && limit.bytes_per_sec > 0.0) {
+            bail!("rate limits have to be above zero, got {text}");
+        }
+        Ok(limit)
+    }
+}
+
+/// Parses `<message type>=<messages per second>/<bytes per second>`, where the message type is the name of a
+/// `ClientServerMsg` variant or `all`.
+pub fn parse_rate_limit_rule(text: &str) -> anyhow::Result<(String, RateLimit)> {
+    let Some((msg_type, limit)) = text.split_once('=') else {
+        bail!("a rate limit rule has to look like <message type>=<messages per second>/<bytes per second>, got {text}");
+    };
+    if msg_type != ALL_MESSAGES && !ClientServerMsg::VARIANT_NAMES.contains(&msg_type) {
+        bail!("unknown message type {msg_type}, expected {ALL_MESSAGES} or one of {}", ClientServerMsg::VARIANT_NAMES.join(", "));
+    }
+    Ok((msg_type.to_owned(), limit.parse()?))
+}
+
+/// Holds up to one second worth of its rate, a single message bigger than that passes once the bucket is full
+/// and leaves it in debt.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount.min(self.rate)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+struct Buckets {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit) -> Buckets {
+        Buckets {
+            messages: TokenBucket::new(limit.messages_per_sec),
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+        }
+    }
+}
+
+pub enum RateDecision {
+    Allowed,
+    /// Carries the number of messages dropped in the current streak.
+    Dropped (u32),
+    Kick,
+}
+
+pub struct SessionRateLimiter {
+    limits: HashMap<String, RateLimit>,
+    buckets: HashMap<&'static str, Buckets>,
+    /// `None` never kicks.
+    kick_after: Option<u32>,
+    drop_streak: u32,
+    last_drop: Option<Instant>,
+}
+
+impl SessionRateLimiter {
+    pub fn new(limits: HashMap<String, RateLimit>, kick_after: Option<u32>) -> SessionRateLimiter {
+        SessionRateLimiter {
+            limits,
+            buckets: HashMap::new(),
+            kick_after,
+            drop_streak: 0,
+            last_drop: None,
+        }
+    }
+
+    /// A message only passes if every bucket it counts against has room for it.
+    pub fn check(&mut self, variant_name: &'static str, bytes: usize) -> RateDecision {
+        if self.limits.is_empty() {
+            return RateDecision::Allowed;
+        }
+        let now = Instant::now();
+        for key in [ALL_MESSAGES, variant_name] {
+            if !self.buckets.contains_key(key) {
+                if let Some(limit) = self.limits.get(key) {
+                    self.buckets.insert(key, Buckets::new(*limit));
+                }
+            }
+        }
+        let mut allowed = true;
+        for key in [ALL_MESSAGES, variant_name] {
+            if let Some(buckets) = self.buckets.get_mut(key) {
+                buckets.messages.refill(now);
+                buckets.bytes.refill(now);
+                allowed &= buckets.messages.has(1.0) && buckets.bytes.has(bytes as f64);
+            }
+        }
+        if allowed {
+            for key in [ALL_MESSAGES, variant_name] {
+                if let Some(buckets) = self.buckets.get_mut(key) {
+                    buckets.messages.take(1.0);
+                    buckets.bytes.take(bytes as f64);
+                }
+            }
+            return RateDecision::Allowed;
+        }
+        if self.last_drop.is_some_and(|last_drop| now.duration_since(last_drop) > DROP_STREAK_RESET) {
+            self.drop_streak = 0;
+        }
+        self.last_drop = Some(now);
+        self.drop_streak += 1;
+        // only once, the session keeps dropping whatever it still sends until the kick took effect
+        if self.kick_after == Some(self.drop_streak) {
+            RateDecision::Kick
+        }
+        else {
+            RateDecision::Dropped (self.drop_streak)
+        }
+    }
+}
+
+/// Returns false if the message has to be dropped, kicks sessions that keep flooding.
+pub fn admit(session_id: u16, info: &SessionInfo, variant_name: &'static str, bytes: usize, context: &ServerContext) -> bool {
+    let decision = info.rate_limiter.lock().unwrap().check(variant_name, bytes);
+    match decision {
+        RateDecision::Allowed => true,
+        RateDecision::Dropped (drop_streak) => {
+            info.rate_limited.fetch_add(1, Ordering::Relaxed);
+            context.metrics.count_rate_limited(variant_name);
+            if drop_streak.is_power_of_two() {
+                warn!(drop_streak, msg_type = variant_name, "over the rate limit, dropping messages");
+            }
+            false
+        }
+        RateDecision::Kick => {
+            info.rate_limited.fetch_add(1, Ordering::Relaxed);
+            context.metrics.count_rate_limited(variant_name);
+            context.metrics.rate_limit_kicks.fetch_add(1, Ordering::Relaxed);
+            warn!("kicking because it kept exceeding the rate limit");
+            context.hub.send(BroadcastMsg::Kick (session_id));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rules: &[&str], kick_after: Option<u32>) -> SessionRateLimiter {
+        let limits = rules.iter().map(|rule| parse_rate_limit_rule(rule).unwrap()).collect();
+        SessionRateLimiter::new(limits, kick_after)
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_one_second_worth() {
+        let mut bucket = TokenBucket::new(10.0);
+        let start = bucket.last_refill;
+        bucket.take(10.0);
+        assert!(!bucket.has(1.0));
+        bucket.refill(start + Duration::from_millis(500));
+        assert!(bucket.has(5.0));
+        assert!(!bucket.has(6.0));
+        bucket.refill(start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn oversized_amounts_pass_a_full_bucket_and_leave_it_in_debt() {
+        let mut bucket = TokenBucket::new(10.0);
+        let start = bucket.last_refill;
+        assert!(bucket.has(25.0));
+        bucket.take(25.0);
+        bucket.refill(start + Duration::from_secs(1));
+        assert!(!bucket.has(1.0));
+    }
+
+    #[test]
+    fn limits_apply_per_message_type_and_to_all() {
+        let mut rate_limiter = limiter(&["Ping=2/1000", "all=3/1000"], Some(100));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Allowed));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Allowed));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Dropped (1)));
+        assert!(matches!(rate_limiter.check("Pong", 8), RateDecision::Allowed));
+        assert!(matches!(rate_limiter.check("Pong", 8), RateDecision::Dropped (2)));
+    }
+
+    #[test]
+    fn bytes_are_limited_too() {
+        let mut rate_limiter = limiter(&["all=100/100"], Some(100));
+        assert!(matches!(rate_limiter.check("Ping", 60), RateDecision::Allowed));
+        assert!(matches!(rate_limiter.check("Ping", 60), RateDecision::Dropped (1)));
+    }
+
+    #[test]
+    fn kicks_once_the_drop_streak_reaches_the_limit() {
+        let mut rate_limiter = limiter(&["all=1/1000"], Some(2));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Allowed));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Dropped (1)));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Kick));
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Dropped (3)));
+    }
+
+    #[test]
+    fn never_kicks_without_a_limit() {
+        let mut rate_limiter = limiter(&["all=1/1000"], None);
+        assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Allowed));
+        for streak in 1..=3 {
+            assert!(matches!(rate_limiter.check("Ping", 8), RateDecision::Dropped (dropped) if dropped == streak));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(parse_rate_limit_rule("Nope=1/1").is_err());
+        assert!(parse_rate_limit_rule("Ping=0/1").is_err());
+        assert!(parse_rate_limit_rule("Ping=1").is_err());
+        assert!(parse_rate_limit_rule("Ping").is_err());
+    }
+}
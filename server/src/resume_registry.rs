@@ -0,0 +1,91 @@
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+use msgs::{client_type::ClientType, resume::{ResumeToken, NO_RESUME_TOKEN}};
+
+/// What a session that lost its connection leaves behind for the client to pick up again.
+pub struct ParkedSession {
+    pub rooms: HashSet<u8>,
+    pub client_type: Option<ClientType>,
+}
+
+pub enum Resumed {
+    /// The old connection ended and the session was waiting for the client.
+    Parked (ParkedSession),
+    /// The old connection did not notice yet that it is dead and gets replaced.
+    Live,
+}
+
+struct ResumeEntry {
+    token: ResumeToken,
+    device_id: u32,
+    parked: Option<ParkedSession>,
+}
+
+/// Resume tokens of the live sessions and of the ended ones still waiting for their client.
+pub struct ResumeRegistry {
+    entries: Mutex<HashMap<u16, ResumeEntry>>,
+}
+
+impl ResumeRegistry {
+    pub fn new() -> ResumeRegistry {
+        ResumeRegistry {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issue(&self, session_id: u16, device_id: u32) -> ResumeToken {
+        let mut token = NO_RESUME_TOKEN;
+        while token == NO_RESUME_TOKEN {
+            token = rand::random();
+        }
+        let entry = ResumeEntry {
+            token,
+            device_id,
+            parked: None,
+        };
+        self.entries.lock().unwrap().insert(session_id, entry);
+        token
+    }
+
+    /// Hands the session over to a new connection of the same device if the token matches.
+    pub fn claim(&self, session_id: u16, device_id: u32, token: &ResumeToken) -> Option<Resumed> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&session_id)?;
+        if entry.token != *token || entry.device_id != device_id {
+            return None;
+        }
+        let entry = entries.remove(&session_id).unwrap();
+        match entry.parked {
+            Some(parked) => Some(Resumed::Parked (parked)),
+            None => Some(Resumed::Live),
+        }
+    }
+
+    /// Whether the session lost its connection and is waiting for the client to resume it.
+    pub fn is_parked(&self, session_id: u16) -> bool {
+        self.entries.lock().unwrap().get(&session_id).is_some_and(|entry| entry.parked.is_some())
+    }
+
+    /// Returns false if a new connection claimed the session in the meantime, it then owns the session id.
+    pub fn park(&self, session_id: u16, token: &ResumeToken, parked: ParkedSession) -> bool {
+        match self.entries.lock().unwrap().get_mut(&session_id) {
+            Some(entry) if entry.token == *token => {
+                entry.parked = Some(parked);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns false if a new connection claimed the session in the meantime, it then owns the session id.
+    pub fn forget(&self, session_id: u16, token: &ResumeToken) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&session_id) {
+            Some(entry) if entry.token == *token => {
+                entries.remove(&session_id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use std::{sync::{Arc, Mutex}, time::SystemTime};
+
+use logging::LogLevelHandle;
+use msgs::model::SharedData;
+use tokio::sync::RwLock;
+use tokio_rustls::TlsAcceptor;
+
+use crate::{config::Config, metrics::Metrics, resume_registry::ResumeRegistry, session_hub::SessionHub, session_ids::SessionIdAllocator, udp_relay::UdpRelay};
+
+/// Server wide state handed to every client process.
+#[derive(Clone)]
+pub struct ServerContext {
+    pub hub: Arc<SessionHub>,
+    pub shared_data: Arc<RwLock<SharedData>>,
+    pub server_start_time: SystemTime,
+    pub config: Arc<Config>,
+    pub metrics: Arc<Metrics>,
+    pub resume_registry: Arc<ResumeRegistry>,
+    pub session_ids: Arc<Mutex<SessionIdAllocator>>,
+    /// Wraps every accepted connection in tls when set.
+    pub tls_acceptor: Option<TlsAcceptor>,
+    /// Relays unreliable messages over udp when set.
+    pub udp_relay: Option<Arc<UdpRelay>>,
+    /// Changes the log filter at runtime, used by the admin api.
+    pub log_level: LogLevelHandle,
+}
@@ -0,0 +1,212 @@
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, RwLock}, time::SystemTime};
+
+use msgs::{client_server_msg::{Address, DEFAULT_ROOM}, client_type::ClientType, latency::LatencyStats};
+
+use tracing::{info_span, warn, Span};
+
+use crate::{broadcast_msg::BroadcastMsg, metrics::Metrics, outbound_queue::{coalesce_key, CloseReason, OutboundQueue, OverflowPolicy, PushResult, QueueStats}, rate_limit::SessionRateLimiter};
+
+/// What the admin api reports about a session, the session keeps the counters up to date itself.
+pub struct SessionInfo {
+    pub device_id: u32,
+    pub peer_addr: SocketAddr,
+    pub connected_at: SystemTime,
+    pub client_type: Mutex<Option<ClientType>>,
+    /// Authenticated with the manager secret.
+    pub manager_credential: bool,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub rate_limiter: Mutex<SessionRateLimiter>,
+    /// Messages dropped because the session exceeded its rate limits.
+    pub rate_limited: AtomicU64,
+    /// `None` until the first latency probe came back.
+    pub latency: Mutex<Option<LatencyStats>>,
+}
+
+impl SessionInfo {
+    pub fn new(device_id: u32, peer_addr: SocketAddr, manager_credential: bool, rate_limiter: SessionRateLimiter) -> SessionInfo {
+        SessionInfo {
+            device_id,
+            peer_addr,
+            connected_at: SystemTime::now(),
+            client_type: Mutex::new(None),
+            manager_credential,
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            rate_limiter: Mutex::new(rate_limiter),
+            rate_limited: AtomicU64::new(0),
+            latency: Mutex::new(None),
+        }
+    }
+
+    /// For logging about the session from outside its own process.
+    pub fn span(&self, session_id: u16) -> Span {
+        info_span!("session", session_id, device_id = self.device_id, peer_addr = %self.peer_addr)
+    }
+}
+
+struct SessionEntry {
+    queue: Arc<OutboundQueue>,
+    rooms: HashSet<u8>,
+    info: Arc<SessionInfo>,
+}
+
+/// Routes every `BroadcastMsg` into the outbound queues of the sessions it is addressed to.
+pub struct SessionHub {
+    sessions: RwLock<HashMap<u16, SessionEntry>>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    shutting_down: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl SessionHub {
+    pub fn new(queue_capacity: usize, overflow_policy: OverflowPolicy, metrics: Arc<Metrics>) -> SessionHub {
+        SessionHub {
+            sessions: RwLock::new(HashMap::new()),
+            queue_capacity,
+            overflow_policy,
+            shutting_down: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// A `resumed` session takes over the rooms and client type of the connection it replaces.
+    pub fn register(&self, session_id: u16, info: Arc<SessionInfo>, resumed: bool) -> Arc<OutboundQueue> {
+        let queue = Arc::new(OutboundQueue::new(self.queue_capacity, self.overflow_policy));
+        let mut entry = SessionEntry {
+            queue: queue.clone(),
+            rooms: HashSet::from([DEFAULT_ROOM]),
+            info,
+        };
+        let mut sessions = self.sessions.write().unwrap();
+        if self.shutting_down.load(Ordering::Relaxed) {
+            queue.close(CloseReason::Shutdown);
+        }
+        if let Some(replaced) = sessions.remove(&session_id) {
+            if resumed {
+                entry.rooms = replaced.rooms;
+                *entry.info.client_type.lock().unwrap() = *replaced.info.client_type.lock().unwrap();
+            }
+            replaced.queue.close(CloseReason::Replaced);
+        }
+        sessions.insert(session_id, entry);
+        queue
+    }
+
+    /// Only removes the session if `queue` still is its queue, a resumed connection may have taken over the id.
+    /// Returns the rooms the session was in.
+    pub fn unregister(&self, session_id: u16, queue: &Arc<OutboundQueue>) -> Option<HashSet<u8>> {
+        let mut sessions = self.sessions.write().unwrap();
+        if !sessions.get(&session_id).is_some_and(|entry| Arc::ptr_eq(&entry.queue, queue)) {
+            return None;
+        }
+        let entry = sessions.remove(&session_id).unwrap();
+        entry.queue.close(CloseReason::Ended);
+        Some(entry.rooms)
+    }
+
+    pub fn set_rooms(&self, session_id: u16, rooms: HashSet<u8>) {
+        if let Some(entry) = self.sessions.write().unwrap().get_mut(&session_id) {
+            entry.rooms = rooms;
+        }
+    }
+
+    pub fn rooms(&self, session_id: u16) -> HashSet<u8> {
+        self.sessions.read().unwrap().get(&session_id).map(|entry| entry.rooms.clone()).unwrap_or_default()
+    }
+
+    pub fn session_info(&self, session_id: u16) -> Option<Arc<SessionInfo>> {
+        self.sessions.read().unwrap().get(&session_id).map(|entry| entry.info.clone())
+    }
+
+    /// Live sessions ordered by session id.
+    pub fn sessions_info(&self) -> Vec<(u16, Arc<SessionInfo>)> {
+        let mut sessions_info = self.sessions.read().unwrap().iter()
+            .map(|(&session_id, entry)| (session_id, entry.info.clone()))
+            .collect::<Vec<_>>();
+        sessions_info.sort_by_key(|(session_id, _)| *session_id);
+        sessions_info
+    }
+
+    /// Like `sessions_info`, with the stats of each session's outbound queue.
+    pub fn sessions_queue_stats(&self) -> Vec<(u16, Arc<SessionInfo>, QueueStats)> {
+        let mut sessions = self.sessions.read().unwrap().iter()
+            .map(|(&session_id, entry)| (session_id, entry.info.clone(), entry.queue.stats()))
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|(session_id, _, _)| *session_id);
+        sessions
+    }
+
+    /// The sessions `address` includes.
+    pub fn recipients(&self, address: Address) -> Vec<u16> {
+        self.sessions.read().unwrap().iter()
+            .filter(|(&session_id, entry)| address.includes(session_id, &entry.rooms))
+            .map(|(&session_id, _)| session_id)
+            .collect()
+    }
+
+    /// Returns false if the session already was in the room.
+    pub fn join_room(&self, session_id: u16, room: u8) -> bool {
+        match self.sessions.write().unwrap().get_mut(&session_id) {
+            Some(entry) => entry.rooms.insert(room),
+            None => false,
+        }
+    }
+
+    pub fn leave_room(&self, session_id: u16, room: u8) {
+        if let Some(entry) = self.sessions.write().unwrap().get_mut(&session_id) {
+            entry.rooms.remove(&room);
+        }
+    }
+
+    /// Lets every session deliver what is already queued and then end, sessions registering later end right away.
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        for entry in self.sessions.read().unwrap().values() {
+            entry.queue.close(CloseReason::Shutdown);
+        }
+    }
+
+    pub fn send(&self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::Send (address, output_buffer) => {
+                let bytes: Arc<[u8]> = output_buffer.into();
+                let coalesce_key = match self.overflow_policy {
+                    OverflowPolicy::Coalesce => coalesce_key(&bytes),
+                    _ => None,
+                };
+                let sessions = self.sessions.read().unwrap();
+                for (&session_id, entry) in sessions.iter() {
+                    if !address.includes(session_id, &entry.rooms) {
+                        continue;
+                    }
+                    match entry.queue.push(bytes.clone(), coalesce_key) {
+                        PushResult::Queued | PushResult::Closed => {}
+                        PushResult::Coalesced => {
+                            self.metrics.outbound_coalesced.fetch_add(1, Ordering::Relaxed);
+                        }
+                        PushResult::Dropped => {
+                            self.metrics.outbound_dropped.fetch_add(1, Ordering::Relaxed);
+                            let stats = entry.queue.stats();
+                            if stats.dropped.is_power_of_two() {
+                                entry.info.span(session_id).in_scope(|| {
+                                    warn!(depth = stats.depth, dropped = stats.dropped, "outbound queue full, dropping messages");
+                                });
+                            }
+                        }
+                        PushResult::Disconnected => {
+                            self.metrics.outbound_overflow_disconnects.fetch_add(1, Ordering::Relaxed);
+                            entry.info.span(session_id).in_scope(|| warn!("outbound queue full, disconnecting"));
+                        }
+                    }
+                }
+            }
+            BroadcastMsg::Kick (to_kick) => {
+                if let Some(entry) = self.sessions.read().unwrap().get(&to_kick) {
+                    entry.queue.close(CloseReason::Kicked);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use msgs::server_client_msg::NO_OWNER;
+
+/// Hands out session ids round robin, skipping ids that are in use or were freed less than `quarantine` ago
+/// so peers holding on to an old id do not reach a stranger. `NO_OWNER` is never handed out.
+pub struct SessionIdAllocator {
+    in_use: HashSet<u16>,
+    quarantined: HashMap<u16, Instant>,
+    quarantine: Duration,
+    cursor: u16,
+}
+
+impl SessionIdAllocator {
+    pub fn new(quarantine: Duration, cursor: u16) -> SessionIdAllocator {
+        SessionIdAllocator {
+            in_use: HashSet::new(),
+            quarantined: HashMap::new(),
+            quarantine,
+            cursor: cursor % NO_OWNER,
+        }
+    }
+
+    /// Returns `None` when every id is in use or quarantined.
+    pub fn allocate(&mut self) -> Option<u16> {
+        let now = Instant::now();
+        let quarantine = self.quarantine;
+        self.quarantined.retain(|_, freed_at| now.duration_since(*freed_at) < quarantine);
+        for _ in 0..NO_OWNER {
+            let session_id = self.cursor;
+            self.cursor = (self.cursor + 1) % NO_OWNER;
+            if self.in_use.contains(&session_id) || self.quarantined.contains_key(&session_id) {
+                continue;
+            }
+            self.in_use.insert(session_id);
+            return Some(session_id);
+        }
+        None
+    }
+
+    /// Marks an id handed out before a restart as in use.
+    pub fn reserve(&mut self, session_id: u16) {
+        if session_id != NO_OWNER {
+            self.in_use.insert(session_id);
+        }
+    }
+
+    pub fn release(&mut self, session_id: u16) {
+        if self.in_use.remove(&session_id) {
+            self.quarantined.insert(session_id, Instant::now());
+        }
+    }
+
+    /// Where the next search starts, persisted in snapshots so a restart does not hand out recent ids first.
+    pub fn cursor(&self) -> u16 {
+        self.cursor
+    }
+
+    pub fn in_use_count(&self) -> usize {
+        self.in_use.len()
+    }
+}
+
+/// Releases its session id when dropped unless the id was handed over to someone else.
+pub struct SessionIdLease {
+    session_id: u16,
+    allocator: Option<Arc<Mutex<SessionIdAllocator>>>,
+}
+
+impl SessionIdLease {
+    pub fn new(session_id: u16, allocator: Arc<Mutex<SessionIdAllocator>>) -> SessionIdLease {
+        SessionIdLease {
+            session_id,
+            allocator: Some(allocator),
+        }
+    }
+
+    /// Keeps the id in use, whoever took over the session releases it.
+    pub fn hand_over(mut self) {
+        self.allocator = None;
+    }
+}
+
+impl Drop for SessionIdLease {
+    fn drop(&mut self) {
+        if let Some(allocator) = self.allocator.take() {
+            allocator.lock().unwrap().release(self.session_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_ids_round_robin() {
+        let mut allocator = SessionIdAllocator::new(Duration::ZERO, 0);
+        assert_eq!(allocator.allocate(), Some(0));
+        assert_eq!(allocator.allocate(), Some(1));
+        allocator.release(0);
+        assert_eq!(allocator.allocate(), Some(2));
+    }
+
+    #[test]
+    fn skips_reserved_ids_and_wraps_before_no_owner() {
+        let mut allocator = SessionIdAllocator::new(Duration::ZERO, NO_OWNER - 1);
+        allocator.reserve(0);
+        allocator.reserve(NO_OWNER);
+        assert_eq!(allocator.allocate(), Some(NO_OWNER - 1));
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(allocator.in_use_count(), 3);
+    }
+
+    #[test]
+    fn quarantined_ids_are_not_reused() {
+        let mut allocator = SessionIdAllocator::new(Duration::from_secs(3600), 0);
+        for _ in 0..NO_OWNER {
+            allocator.allocate().unwrap();
+        }
+        assert_eq!(allocator.allocate(), None);
+        allocator.release(5);
+        assert_eq!(allocator.allocate(), None);
+    }
+
+    #[test]
+    fn freed_ids_come_back_after_the_quarantine() {
+        let mut allocator = SessionIdAllocator::new(Duration::ZERO, 0);
+        for _ in 0..NO_OWNER {
+            allocator.allocate().unwrap();
+        }
+        allocator.release(5);
+        assert_eq!(allocator.allocate(), Some(5));
+    }
+
+    #[test]
+    fn lease_releases_unless_handed_over() {
+        let allocator = Arc::new(Mutex::new(SessionIdAllocator::new(Duration::ZERO, 0)));
+        let first = allocator.lock().unwrap().allocate().unwrap();
+        let second = allocator.lock().unwrap().allocate().unwrap();
+        drop(SessionIdLease::new(first, allocator.clone()));
+        SessionIdLease::new(second, allocator.clone()).hand_over();
+        assert_eq!(allocator.lock().unwrap().in_use_count(), 1);
+    }
+}
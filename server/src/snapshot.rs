@@ -0,0 +1,219 @@
+use std::{collections::HashSet, io::{Cursor, Read, Write}, path::{Path, PathBuf}, time::Duration};
+
+use anyhow::{bail, Context};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use msgs::model::{Fact, SharedData};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::{ownership::release, server_context::ServerContext};
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"MUCO";
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Everything the server needs to pick up where a previous run left off.
+pub struct Snapshot {
+    pub shared_data: SharedData,
+    pub next_session_id: u16,
+}
+
+/// Layout: magic, format version, next session id, facts, data owners, per room high water of removed versions. All numbers little endian.
+/// Ephemeral facts are left out, the sessions that wrote them do not survive a restart. Their versions count like those of removed facts.
+pub fn encode_snapshot(shared_data: &SharedData, next_session_id: u16) -> Vec<u8> {
+    let is_persistent = |key: &(u8, u16, u16)| !shared_data.ephemeral_writers.contains_key(key);
+    let facts = shared_data.model.facts.iter().filter(|(key, _)| is_persistent(key)).collect::<Vec<_>>();
+    let data_owners = shared_data.data_owners.iter().filter(|(key, _)| is_persistent(key)).collect::<Vec<_>>();
+    let mut removed_high_water = shared_data.model.removed_high_water.clone();
+    for ((room, _, _), fact) in shared_data.model.facts.iter().filter(|(key, _)| !is_persistent(key)) {
+        let high_water = removed_high_water.entry(*room).or_insert(0);
+        *high_water = (*high_water).max(fact.version);
+    }
+    let mut wtr = Vec::new();
+    wtr.write_all(SNAPSHOT_MAGIC).unwrap();
+    wtr.write_u32::<LittleEndian>(SNAPSHOT_FORMAT_VERSION).unwrap();
+    wtr.write_u16::<LittleEndian>(next_session_id).unwrap();
+    wtr.write_u32::<LittleEndian>(facts.len() as u32).unwrap();
+    for ((room, creator_id, index), fact) in facts {
+        wtr.write_u8(*room).unwrap();
+        wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+        wtr.write_u16::<LittleEndian>(*index).unwrap();
+        wtr.write_u32::<LittleEndian>(fact.version).unwrap();
+        wtr.write_u32::<LittleEndian>(fact.data.len() as u32).unwrap();
+        wtr.write_all(&fact.data).unwrap();
+    }
+    wtr.write_u32::<LittleEndian>(data_owners.len() as u32).unwrap();
+    for ((room, creator_id, index), owner_id) in data_owners {
+        wtr.write_u8(*room).unwrap();
+        wtr.write_u16::<LittleEndian>(*creator_id).unwrap();
+        wtr.write_u16::<LittleEndian>(*index).unwrap();
+        wtr.write_u16::<LittleEndian>(*owner_id).unwrap();
+    }
+    wtr.write_u32::<LittleEndian>(removed_high_water.len() as u32).unwrap();
+    for (room, version) in removed_high_water {
+        wtr.write_u8(room).unwrap();
+        wtr.write_u32::<LittleEndian>(version).unwrap();
+    }
+    wtr
+}
+
+pub fn decode_snapshot(bytes: &[u8]) -> anyhow::Result<Snapshot> {
+    let mut rdr = Cursor::new(bytes);
+    let mut magic = [0; 4];
+    rdr.read_exact(&mut magic).context("snapshot is truncated")?;
+    if &magic != SNAPSHOT_MAGIC {
+        bail!("not a snapshot file");
+    }
+    let format_version = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")?;
+    if format_version != SNAPSHOT_FORMAT_VERSION {
+        bail!("unsupported snapshot format version: {format_version}");
+    }
+    let mut shared_data = SharedData::new();
+    let next_session_id = rdr.read_u16::<LittleEndian>().context("snapshot is truncated")?;
+    let facts_count = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")?;
+    for _ in 0..facts_count {
+        let room = rdr.read_u8().context("snapshot is truncated")?;
+        let creator_id = rdr.read_u16::<LittleEndian>().context("snapshot is truncated")?;
+        let index = rdr.read_u16::<LittleEndian>().context("snapshot is truncated")?;
+        let version = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")?;
+        let len = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")? as usize;
+        let mut data = vec![0; len];
+        rdr.read_exact(&mut data).context("snapshot is truncated")?;
+        shared_data.model.facts.insert((room, creator_id, index), Fact { version, data: data.into_boxed_slice() });
+    }
+    let owners_count = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")?;
+    for _ in 0..owners_count {
+        let room = rdr.read_u8().context("snapshot is truncated")?;
+        let creator_id = rdr.read_u16::<LittleEndian>().context("snapshot is truncated")?;
+        let index = rdr.read_u16::<LittleEndian>().context("snapshot is truncated")?;
+        let owner_id = rdr.read_u16::<LittleEndian>().context("snapshot is truncated")?;
+        shared_data.data_owners.insert((room, creator_id, index), owner_id);
+    }
+    let rooms_count = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")?;
+    for _ in 0..rooms_count {
+        let room = rdr.read_u8().context("snapshot is truncated")?;
+        let version = rdr.read_u32::<LittleEndian>().context("snapshot is truncated")?;
+        shared_data.model.raise_removed_high_water(room, version);
+    }
+    Ok(Snapshot {
+        shared_data,
+        next_session_id,
+    })
+}
+
+/// Returns `None` when there is no snapshot yet.
+pub fn read_snapshot(path: &Path) -> anyhow::Result<Option<Snapshot>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read snapshot {}", path.display())),
+    };
+    let snapshot = decode_snapshot(&bytes).with_context(|| format!("failed to restore snapshot {}", path.display()))?;
+    Ok(Some(snapshot))
+}
+
+/// Writes next to the snapshot first so a crash mid write never leaves a half written snapshot behind.
+pub async fn write_snapshot(path: &Path, context: &ServerContext) -> anyhow::Result<()> {
+    let next_session_id = context.session_ids.lock().unwrap().cursor();
+    let bytes = {
+        let lock = context.shared_data.read().await;
+        encode_snapshot(&lock, next_session_id)
+    };
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Writes a snapshot every `period` off the accept loop, the first one after a full period.
+pub fn spawn_periodic_snapshot(path: PathBuf, period: Duration, context: ServerContext) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = write_snapshot(&path, &context).await {
+                error!("failed to write snapshot: {e}");
+            }
+        }
+    })
+}
+
+/// The sessions that owned data before the restart are gone for good, their ownerships and session ids get
+/// released once the clients had `grace` to reconnect and claim them again.
+pub fn spawn_restored_owner_release(context: ServerContext, restored_owners: Vec<((u8, u16, u16), u16)>, grace: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        let mut lock = context.shared_data.write().await;
+        let mut released_count = 0;
+        let restored_owner_ids = restored_owners.iter().map(|(_, owner_id)| *owner_id).collect::<HashSet<_>>();
+        for ((room, creator_id, index), owner_id) in restored_owners {
+            if lock.data_owners.get(&(room, creator_id, index)) != Some(&owner_id) {
+                continue;
+            }
+            release(&mut lock, (room, creator_id, index), owner_id, &context);
+            released_count += 1;
+        }
+        drop(lock);
+        let mut session_ids = context.session_ids.lock().unwrap();
+        for owner_id in restored_owner_ids {
+            session_ids.release(owner_id);
+        }
+        if released_count > 0 {
+            info!(released_count, "released ownerships restored from the snapshot");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use msgs::model::FIRST_FACT_VERSION;
+
+    use super::*;
+
+    fn fact(snapshot: &Snapshot, key: (u8, u16, u16)) -> Option<(u32, &[u8])> {
+        snapshot.shared_data.model.facts.get(&key).map(|fact| (fact.version, &fact.data[..]))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut shared_data = SharedData::new();
+        shared_data.model.set((0, 1, 2), vec![1, 2, 3].into_boxed_slice());
+        shared_data.model.set((0, 1, 2), vec![4].into_boxed_slice());
+        shared_data.model.set((3, 4, 5), Box::new([]));
+        shared_data.model.set((6, 7, 8), Box::new([]));
+        shared_data.model.remove((6, 7, 8));
+        shared_data.data_owners.insert((3, 4, 5), 9);
+
+        let snapshot = decode_snapshot(&encode_snapshot(&shared_data, 42)).unwrap();
+        assert_eq!(snapshot.next_session_id, 42);
+        assert_eq!(snapshot.shared_data.model.facts.len(), 2);
+        assert_eq!(fact(&snapshot, (0, 1, 2)), Some((FIRST_FACT_VERSION + 1, &[4][..])));
+        assert_eq!(fact(&snapshot, (3, 4, 5)), Some((FIRST_FACT_VERSION, &[][..])));
+        assert_eq!(snapshot.shared_data.data_owners, shared_data.data_owners);
+        assert_eq!(snapshot.shared_data.model.removed_high_water, shared_data.model.removed_high_water);
+    }
+
+    #[test]
+    fn ephemeral_facts_and_their_owners_are_left_out() {
+        let mut shared_data = SharedData::new();
+        shared_data.model.set((0, 1, 1), vec![1].into_boxed_slice());
+        shared_data.model.set((0, 1, 2), vec![2].into_boxed_slice());
+        shared_data.ephemeral_writers.insert((0, 1, 2), 5);
+        shared_data.data_owners.insert((0, 1, 1), 5);
+        shared_data.data_owners.insert((0, 1, 2), 5);
+
+        let snapshot = decode_snapshot(&encode_snapshot(&shared_data, 0)).unwrap();
+        assert!(fact(&snapshot, (0, 1, 1)).is_some());
+        assert!(fact(&snapshot, (0, 1, 2)).is_none());
+        assert_eq!(snapshot.shared_data.data_owners.len(), 1);
+        assert_eq!(snapshot.shared_data.data_owners.get(&(0, 1, 1)), Some(&5));
+        assert_eq!(snapshot.shared_data.model.removed_high_water.get(&0), Some(&FIRST_FACT_VERSION));
+    }
+
+    #[test]
+    fn rejects_unknown_format_versions() {
+        let mut bytes = encode_snapshot(&SharedData::new(), 0);
+        bytes[4..8].copy_from_slice(&(SNAPSHOT_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(decode_snapshot(&bytes).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use msgs::tls::CertificateFingerprint;
+use rustls::{crypto::ring, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Loads the certificate chain and private key from pem files, returns the fingerprint clients have to pin as well.
+pub fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<(TlsAcceptor, CertificateFingerprint)> {
+    let certificates = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificate {}", cert_path.display()))?;
+    let Some(end_entity) = certificates.first() else {
+        anyhow::bail!("no certificate in {}", cert_path.display());
+    };
+    let fingerprint = CertificateFingerprint::of_certificate(end_entity);
+    let key = PrivateKeyDer::from_pem_file(key_path).with_context(|| format!("failed to read private key {}", key_path.display()))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .context("certificate and private key do not fit together")?;
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}
@@ -0,0 +1,190 @@
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr}, sync::{atomic::Ordering, Arc, Mutex}};
+
+use byteorder::{ByteOrder, LittleEndian};
+use msgs::{client_server_msg::{Address, ClientServerMsg}, server_client_msg::ServerClientMsg, udp_channel::{decode_client_datagram, is_newer, pack_server_datagram, UdpToken}};
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tracing::warn;
+
+use crate::{broadcast_msg::BroadcastMsg, rate_limit::admit, server_context::ServerContext, session_hub::SessionHub};
+
+struct UdpPeer {
+    token: UdpToken,
+    /// Where the client's datagrams come from, `None` until the first one arrived.
+    addr: Option<SocketAddr>,
+    latest_received: Option<u32>,
+}
+
+/// Relays `UnreliableMessageTo` over udp to the sessions that opened the udp channel and through the
+/// outbound queue to everyone else.
+pub struct UdpRelay {
+    socket: UdpSocket,
+    port: u16,
+    peers: Mutex<HashMap<u16, UdpPeer>>,
+    /// Numbers what every sender relays, whichever way it came in, so receivers can keep only the latest.
+    /// Never reset, a session id that gets reused simply continues counting.
+    sequences: Mutex<HashMap<u16, u32>>,
+}
+
+impl UdpRelay {
+    pub async fn bind(port: u16) -> std::io::Result<UdpRelay> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let port = socket.local_addr()?.port();
+        Ok(UdpRelay {
+            socket,
+            port,
+            peers: Mutex::new(HashMap::new()),
+            sequences: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the token the client has to put in its datagrams, replaces the channel of an earlier connection.
+    pub fn open(&self, session_id: u16) -> UdpToken {
+        let token = rand::random();
+        self.peers.lock().unwrap().insert(session_id, UdpPeer { token, addr: None, latest_received: None });
+        token
+    }
+
+    /// Only closes the channel if `token` still belongs to it, a resumed connection may have opened a new one.
+    pub fn close(&self, session_id: u16, token: &UdpToken) {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.get(&session_id).is_some_and(|peer| peer.token == *token) {
+            peers.remove(&session_id);
+        }
+    }
+
+    /// Checks the token, drops datagrams older than the latest one and remembers where accepted ones came from.
+    /// A replayed old datagram must not redirect the session's downstream to the replaying address.
+    fn accept(&self, session_id: u16, token: &UdpToken, sequence: u32, addr: SocketAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(peer) = peers.get_mut(&session_id).filter(|peer| peer.token == *token) else {
+            return false;
+        };
+        if peer.latest_received.is_some_and(|latest| !is_newer(sequence, latest)) {
+            return false;
+        }
+        peer.latest_received = Some(sequence);
+        peer.addr = Some(addr);
+        true
+    }
+
+    pub fn relay(&self, sender: u16, address: Address, payload: &[u8], hub: &SessionHub) {
+        let sequence = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let sequence = sequences.entry(sender).or_insert(0);
+            *sequence = sequence.wrapping_add(1);
+            *sequence
+        };
+        let mut frame = Vec::new();
+        ServerClientMsg::InterClient (sender, payload).pack(&mut frame);
+        let mut datagram = Vec::new();
+        pack_server_datagram(&mut datagram, sequence, &frame);
+
+        let udp_addrs = self.peers.lock().unwrap().iter()
+            .filter_map(|(&session_id, peer)| Some((session_id, peer.addr?)))
+            .collect::<HashMap<_, _>>();
+        for recipient in hub.recipients(address) {
+            match udp_addrs.get(&recipient) {
+                // a full socket buffer loses the datagram, the next pose update replaces it anyway
+                Some(addr) => {
+                    let _ = self.socket.try_send_to(&datagram, *addr);
+                }
+                None => hub.send(BroadcastMsg::Send (Address::Client (recipient), frame.clone())),
+            }
+        }
+    }
+}
+
+/// Receives the datagrams of all sessions, only pings and `UnreliableMessageTo` are accepted over udp.
+pub fn spawn_udp_relay_process(udp_relay: Arc<UdpRelay>, context: ServerContext) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let ping_type_index = ClientServerMsg::Ping.type_index();
+        let unreliable_type_index = ClientServerMsg::UnreliableMessageTo (Address::All, &[]).type_index();
+        let mut static_buffer = [0; 65536];
+        loop {
+            let (len, addr) = match udp_relay.socket.recv_from(&mut static_buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("error while receiving over udp: {e}");
+                    continue;
+                }
+            };
+            let datagram = &static_buffer[..len];
+            let Some((session_id, token, sequence, body)) = decode_client_datagram(datagram) else {
+                context.metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            if !udp_relay.accept(session_id, &token, sequence, addr) {
+                continue;
+            }
+            if body.len() < 4 || ![ping_type_index, unreliable_type_index].contains(&LittleEndian::read_u32(body)) {
+                continue;
+            }
+            let msg = match ClientServerMsg::decode(body, session_id) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    context.metrics.decode_errors.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            context.metrics.count_received(msg.variant_name(), len);
+            let Some(info) = context.hub.session_info(session_id) else { continue };
+            info.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+            let _span = info.span(session_id).entered();
+            if !admit(session_id, &info, msg.variant_name(), len, &context) {
+                continue;
+            }
+            match msg {
+                ClientServerMsg::Ping => {
+                    let mut frame = Vec::new();
+                    ServerClientMsg::Pong.pack(&mut frame);
+                    let mut datagram = Vec::new();
+                    pack_server_datagram(&mut datagram, 0, &frame);
+                    let _ = udp_relay.socket.try_send_to(&datagram, addr);
+                }
+                ClientServerMsg::UnreliableMessageTo (address, payload) => udp_relay.relay(session_id, address, payload, &context.hub),
+                _ => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_addr(udp_relay: &UdpRelay, session_id: u16) -> Option<SocketAddr> {
+        udp_relay.peers.lock().unwrap().get(&session_id).and_then(|peer| peer.addr)
+    }
+
+    #[tokio::test]
+    async fn replayed_datagrams_do_not_move_the_peer() {
+        let udp_relay = UdpRelay::bind(0).await.unwrap();
+        let token = udp_relay.open(1);
+        let client = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let attacker = SocketAddr::from(([10, 0, 0, 2], 4000));
+
+        assert!(udp_relay.accept(1, &token, 5, client));
+        assert!(!udp_relay.accept(1, &token, 4, attacker));
+        assert!(!udp_relay.accept(1, &token, 5, attacker));
+        assert_eq!(peer_addr(&udp_relay, 1), Some(client));
+
+        let roamed = SocketAddr::from(([10, 0, 0, 1], 4001));
+        assert!(udp_relay.accept(1, &token, 6, roamed));
+        assert_eq!(peer_addr(&udp_relay, 1), Some(roamed));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_tokens() {
+        let udp_relay = UdpRelay::bind(0).await.unwrap();
+        let token = udp_relay.open(1);
+        let mut wrong_token = token;
+        wrong_token[0] ^= 1;
+        assert!(!udp_relay.accept(1, &wrong_token, 1, SocketAddr::from(([10, 0, 0, 2], 4000))));
+        assert!(!udp_relay.accept(2, &token, 1, SocketAddr::from(([10, 0, 0, 2], 4000))));
+        assert_eq!(peer_addr(&udp_relay, 1), None);
+    }
+}
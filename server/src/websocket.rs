@@ -0,0 +1,168 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use futures::{SinkExt, StreamExt};
+use msgs::{dequeue::dequeue_msg, relay_stream::BoxedRelayStream};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, sync::mpsc, time::Duration};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+use warp::{ws::{Message, WebSocket}, Filter, Rejection, Reply};
+
+/// Big enough to hold a hello with a large model without the session waiting on the bridge.
+const BRIDGE_BUFFER_SIZE: usize = 64 * 1024;
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts browser clients, every websocket gets bridged to a byte stream and handed to `connections`
+/// so it runs through the same session code as a tcp client. With a `tls_acceptor` the endpoint speaks wss.
+pub fn spawn_websocket_endpoint(addr: SocketAddr, tls_acceptor: Option<TlsAcceptor>, connections: mpsc::Sender<(BoxedRelayStream, SocketAddr)>) -> anyhow::Result<SocketAddr> {
+    let Some(tls_acceptor) = tls_acceptor else {
+        let (addr, server) = warp::serve(websocket_route(None, connections)).try_bind_ephemeral(addr)?;
+        info!("websocket endpoint listening on {addr}");
+        tokio::spawn(server);
+        return Ok(addr);
+    };
+
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+    info!("websocket endpoint listening on {addr} (tls)");
+    tokio::spawn(async move {
+        loop {
+            let (socket, remote) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("failed to accept websocket connection: {e}");
+                    continue;
+                }
+            };
+            let tls_acceptor = tls_acceptor.clone();
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        warn!(peer_addr = %remote, "tls handshake failed: {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!(peer_addr = %remote, "tls handshake timed out");
+                        return;
+                    }
+                };
+                // warp can't see the peer address behind our own listener, so the route gets it handed in
+                let service = warp::service(websocket_route(Some(remote), connections));
+                if let Err(e) = hyper::server::conn::Http::new().serve_connection(stream, service).with_upgrades().await {
+                    warn!(peer_addr = %remote, "error while serving websocket connection: {e}");
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Upgrades requests on `/` to websockets, `peer` stands in for the remote address when warp doesn't know it.
+fn websocket_route(peer: Option<SocketAddr>, connections: mpsc::Sender<(BoxedRelayStream, SocketAddr)>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path::end()
+        .and(warp::ws())
+        .and(warp::addr::remote())
+        .map(move |ws: warp::ws::Ws, remote: Option<SocketAddr>| {
+            let connections = connections.clone();
+            let remote = remote.or(peer).unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+            ws.on_upgrade(move |websocket| bridge(websocket, remote, connections))
+        })
+}
+
+/// Binary messages from the browser are fed into the stream as they are, what the session writes is split
+/// back into frames so every websocket message carries exactly one `ServerClientMsg`.
+async fn bridge(websocket: WebSocket, remote: SocketAddr, connections: mpsc::Sender<(BoxedRelayStream, SocketAddr)>) {
+    let (session_end, bridge_end) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+    if connections.send((Box::new(session_end), remote)).await.is_err() {
+        return;
+    }
+    let (mut ws_sender, mut ws_receiver) = websocket.split();
+    let (mut reader, mut writer) = tokio::io::split(bridge_end);
+
+    let inbound = async {
+        while let Some(result) = ws_receiver.next().await {
+            let msg = match result {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!(peer_addr = %remote, "error while receiving from websocket: {e}");
+                    break;
+                }
+            };
+            if msg.is_close() {
+                break;
+            }
+            if !msg.is_binary() {
+                continue;
+            }
+            if writer.write_all(msg.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let outbound = async {
+        let mut static_buffer = [0; 4096];
+        let mut output_buffer = Vec::new();
+        loop {
+            let len = match reader.read(&mut static_buffer).await {
+                Ok(len) if len > 0 => len,
+                _ => break,
+            };
+            output_buffer.extend(&static_buffer[..len]);
+            while let Some((_, end)) = dequeue_msg(&output_buffer) {
+                let frame = output_buffer.drain(..end).collect::<Vec<_>>();
+                if let Err(e) = ws_sender.send(Message::binary(frame)).await {
+                    warn!(peer_addr = %remote, "error while sending to websocket: {e}");
+                    return;
+                }
+            }
+        }
+        let _ = ws_sender.close().await;
+    };
+
+    // whichever side ends first ends the bridge, dropping it closes the other one
+    tokio::select! {
+        _ = inbound => {}
+        _ = outbound => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use msgs::tls::{connect_tls, pinned_tls_connector};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::tls::load_tls_acceptor;
+
+    #[tokio::test]
+    async fn bridges_secure_websockets() {
+        let test_data = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data");
+        let (tls_acceptor, fingerprint) = load_tls_acceptor(&test_data.join("cert.pem"), &test_data.join("key.pem")).unwrap();
+        let (connections, mut incoming) = mpsc::channel(1);
+        let addr = spawn_websocket_endpoint(SocketAddr::from(([127, 0, 0, 1], 0)), Some(tls_acceptor), connections).unwrap();
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let client_addr = socket.local_addr().unwrap();
+        let mut stream = connect_tls(&pinned_tls_connector(fingerprint), socket).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").await.unwrap();
+        let mut response = vec![0; 1024];
+        let len = stream.read(&mut response).await.unwrap();
+        assert!(response[..len].starts_with(b"HTTP/1.1 101"));
+
+        let (mut session_end, remote) = incoming.recv().await.unwrap();
+        assert_eq!(remote, client_addr);
+
+        // a masked binary frame, the zero mask leaves the payload as it is
+        stream.write_all(&[0x82, 0x83, 0, 0, 0, 0, 1, 2, 3]).await.unwrap();
+        let mut payload = [0; 3];
+        session_end.read_exact(&mut payload).await.unwrap();
+        assert_eq!(payload, [1, 2, 3]);
+    }
+}